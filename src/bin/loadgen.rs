@@ -1,6 +1,7 @@
 //! Open-loop load generator for the Simple RPC server.
 //! Usage:
-//!   cargo run --bin loadgen -- [addr] [rps] [duration_secs]
+//!   cargo run --bin loadgen -- [addr[,addr...]] [rps] [duration_secs] [mode] [--no-verify] [--tcp-connect-timeout=<ms>] [--hgrm PATH]
+//!                              [--p99-target-ms N] [--max-error-rate F] [--connect-only [--handshake]]
 //! Example:
 //!   cargo run --bin loadgen -- 127.0.0.1:8080 200 30
 //!
@@ -10,12 +11,31 @@
 //!   - 10% matrix_multiply 16x16
 //!   - 20% compress_data zlib on 512B
 //!
-//! Prints summary stats and writes CSV to results/loadgen.csv
+//! Single-op modes (4th arg): hash, sort, matmul, compress, kmeans, rle, stats
+//!
+//! Several comma-separated addresses spread the connection pool across servers round-robin,
+//! for load-testing a cluster without a load balancer; per-address totals are reported too.
+//!
+//! `--no-verify` skips decoding/checking results, for when the client machine is the bottleneck.
+//! `--tcp-connect-timeout=<ms>` bounds each pool connection attempt (default 10s).
+//! `--hgrm PATH` also exports the latency histogram (milliseconds) as an HdrHistogram interval
+//! log, with the `.hgrm` percentile distribution included as comment lines.
+//! `--p99-target-ms N` and `--max-error-rate F` (a fraction, e.g. 0.01) are SLOs checked after the
+//! run: each one violated is printed and the process exits nonzero, for use as a CI gate.
+//! `--connect-only` sends no requests: each tick opens a fresh connection and closes it, and the
+//! latencies recorded are connection setup times, to measure accept-loop cost on its own. With
+//! `--handshake` each connection also completes a `hello` before closing.
+//!
+//! Prints summary stats (including per-connection completed counts and max in-flight, to
+//! spot a connection that serializes the run) and streams every latency to results/loadgen.csv
+//! during the run, keeping only a histogram in memory. results/loadgen.json has the summary plus
+//! completed requests per second of the run (with min/max/avg), to show whether throughput held up.
 
 use anyhow::Result;
 use rand::{Rng, SeedableRng};
 use rand::rngs::StdRng;
 use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
@@ -25,31 +45,103 @@ use tracing::{info, warn};
 
 // Minimal copy of the client to avoid cross-bin linking.
 mod client_shim {
-    pub use simple_rpc_rust::{RpcRequest, RpcResponse, read_frame, write_frame};
-    pub use anyhow::{Result, anyhow};
+    pub use simple_rpc_rust::{ClientError, RpcResponse, encode_frame_header, read_frame, tcp_connect, PROTOCOL_VERSION};
+    pub use anyhow::Result;
     pub use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
     //pub use serde_json::json;
     pub use tokio::net::TcpStream;
     pub use tokio::io::AsyncWriteExt;
-    pub use uuid::Uuid;
+    pub use std::time::Duration;
+    use serde::Serialize;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// Spare request frame buffers, cleared, for this worker thread's next requests.
+        static SCRATCH: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Spare buffers each thread keeps; more than one so requests in flight together all reuse.
+    const SCRATCH_PER_THREAD: usize = 4;
+
+    /// An encoded request frame in a buffer taken from the thread's pool. On drop the buffer
+    /// goes back to the pool of whichever thread drops it, unless that pool is full.
+    pub struct Frame(Vec<u8>);
+
+    impl AsRef<[u8]> for Frame {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    impl Drop for Frame {
+        fn drop(&mut self) {
+            let mut buf = std::mem::take(&mut self.0);
+            buf.clear();
+            let _ = SCRATCH.try_with(|bufs| {
+                let mut bufs = bufs.borrow_mut();
+                if bufs.len() < SCRATCH_PER_THREAD {
+                    bufs.push(buf);
+                }
+            });
+        }
+    }
+
+    /// A request as it goes on the wire, borrowing everything it sends.
+    #[derive(Serialize)]
+    struct Request<'a, P> {
+        request_id: &'a str,
+        func: &'a str,
+        params: &'a P,
+    }
+
+    /// Encode a length-prefixed request frame straight from borrowed params into a pooled
+    /// buffer, so a request allocates no `Value` tree, id string or frame of its own.
+    pub fn encode_request<P: Serialize>(func: &str, params: &P) -> Result<Frame> {
+        let mut frame = Frame(SCRATCH.with(|bufs| bufs.borrow_mut().pop()).unwrap_or_default());
+        #[cfg(test)]
+        let had = frame.0.capacity();
+        frame.0.extend_from_slice(&[0; 4]);
+        let mut id = [0u8; uuid::fmt::Hyphenated::LENGTH];
+        let request_id = uuid::Uuid::new_v4().hyphenated().encode_lower(&mut id);
+        serde_json::to_writer(&mut frame.0, &Request { request_id, func, params })?;
+        let len = u32::try_from(frame.0.len() - 4)?;
+        frame.0[..4].copy_from_slice(&encode_frame_header(len));
+        #[cfg(test)]
+        if frame.0.capacity() > had {
+            crate::tests::SCRATCH_GROWS.with(|n| n.set(n.get() + 1));
+        }
+        Ok(frame)
+    }
+
+    #[derive(Serialize)]
+    struct DataParams<'a> { data_base64: &'a str }
+    #[derive(Serialize)]
+    struct CompressParams<'a> { algo: &'a str, data_base64: &'a str }
+    #[derive(Serialize)]
+    struct ValuesParams<'a, T> { values: &'a [T] }
+    #[derive(Serialize)]
+    struct MatrixParams<'a> { n: usize, a: &'a [f64], b: &'a [f64] }
+    #[derive(Serialize)]
+    struct KmeansParams<'a> { points: &'a [Vec<f64>], k: usize, iterations: usize }
 
     pub struct RpcClient {
         sock: TcpStream,
+        /// Decode and check results; off with `--no-verify` so CPU goes to issuing load
+        verify: bool,
     }
     impl RpcClient {
-        pub async fn connect(addr: &str) -> Result<Self> {
-            let sock = TcpStream::connect(addr).await?;
-            sock.set_nodelay(true)?;
-            Ok(Self { sock })
-        }
-                async fn call_raw(&mut self, func: &str, params: serde_json::Value) -> Result<serde_json::Value> {
-            let req = RpcRequest {
-                request_id: uuid::Uuid::new_v4().to_string(),
-                func: func.to_string(),
-                params,
-            };
-            let v = serde_json::to_value(&req)?;
-            write_frame(&mut self.sock, &v).await?;
+        pub async fn connect(addr: &str, verify: bool, connect_timeout: Duration) -> Result<Self> {
+            let sock = tcp_connect(addr, connect_timeout).await?;
+            Ok(Self { sock, verify })
+        }
+        #[cfg(test)]
+        pub fn peer_addr(&self) -> std::net::SocketAddr {
+            self.sock.peer_addr().unwrap()
+        }
+        async fn call_raw<P: Serialize>(&mut self, func: &str, params: &P) -> Result<serde_json::Value> {
+            let frame = encode_request(func, params)?;
+            self.sock.write_all(frame.as_ref()).await?;
+            drop(frame);
             self.sock.flush().await?;
 
             loop {
@@ -57,48 +149,254 @@ mod client_shim {
                 let resp: RpcResponse = serde_json::from_value(resp_v)?;
 
                 match resp {
-                    RpcResponse::Accepted { .. } => {
-                        // two-phase ack; keep waiting for the final result
+                    RpcResponse::Accepted { .. } | RpcResponse::Chunk { .. } => {
+                        // two-phase ack or streamed page; keep waiting for the final result
                         continue;
                     }
-                    RpcResponse::Completed { ok, result, error, .. } => {
-                        if ok {
-                            return Ok(result.unwrap_or(serde_json::json!(null)));
-                        } else {
-                            return Err(anyhow::anyhow!(error.unwrap_or_else(|| "server error".into())));
-                        }
-                    }
-                    RpcResponse::Error { error, .. } => {
-                        return Err(anyhow::anyhow!(error));
-                    }
+                    resp => return Ok(Result::<serde_json::Value, ClientError>::from(resp)?),
                 }
             }
         }
 
-        pub async fn hash_compute(&mut self, data: &[u8]) -> Result<String> {
-            let params = serde_json::json!({ "data_base64": B64.encode(data) });
-            let v = self.call_raw("hash_compute", params).await?;
+        pub async fn hello(&mut self) -> Result<()> {
+            let v = self.call_raw("hello", &serde_json::json!({ "protocol": PROTOCOL_VERSION })).await?;
+            if self.verify && v.get("protocol").and_then(|p| p.as_u64()) != Some(PROTOCOL_VERSION as u64) {
+                anyhow::bail!("server speaks protocol {}", v.get("protocol").unwrap_or(&serde_json::Value::Null));
+            }
+            Ok(())
+        }
+        pub async fn hash_compute(&mut self, data_base64: &str) -> Result<String> {
+            let v = self.call_raw("hash_compute", &DataParams { data_base64 }).await?;
+            if !self.verify { return Ok(Default::default()); }
             Ok(v.get("hex").and_then(|x| x.as_str()).unwrap_or_default().to_string())
         }
-        pub async fn sort_array(&mut self, values: Vec<i32>) -> Result<Vec<i32>> {
-            let params = serde_json::json!({ "values": values });
-            let v = self.call_raw("sort_array", params).await?;
+        pub async fn sort_array(&mut self, values: &[i32]) -> Result<Vec<i32>> {
+            let v = self.call_raw("sort_array", &ValuesParams { values }).await?;
+            if !self.verify { return Ok(Default::default()); }
             let arr = v.get("values").ok_or_else(|| anyhow::anyhow!("missing values"))?;
             Ok(serde_json::from_value(arr.clone())?)
         }
-        pub async fn matrix_multiply(&mut self, n: usize, a: Vec<f64>, b: Vec<f64>) -> Result<Vec<f64>> {
-            let params = serde_json::json!({ "n": n, "a": a, "b": b });
-            let v = self.call_raw("matrix_multiply", params).await?;
+        pub async fn matrix_multiply(&mut self, n: usize, a: &[f64], b: &[f64]) -> Result<Vec<f64>> {
+            let v = self.call_raw("matrix_multiply", &MatrixParams { n, a, b }).await?;
+            if !self.verify { return Ok(Default::default()); }
             let arr = v.get("c").ok_or_else(|| anyhow::anyhow!("missing c"))?;
             Ok(serde_json::from_value(arr.clone())?)
         }
-        pub async fn compress_data(&mut self, algo: &str, data: &[u8]) -> Result<Vec<u8>> {
-            let params = serde_json::json!({ "algo": algo, "data_base64": B64.encode(data) });
-            let v = self.call_raw("compress_data", params).await?;
+        pub async fn compress_data(&mut self, algo: &str, data_base64: &str) -> Result<Vec<u8>> {
+            let v = self.call_raw("compress_data", &CompressParams { algo, data_base64 }).await?;
+            if !self.verify { return Ok(Default::default()); }
             let s = v.get("compressed_base64").and_then(|x| x.as_str()).ok_or_else(|| anyhow::anyhow!("missing compressed_base64"))?;
             Ok(B64.decode(s.as_bytes())?)
         }
+        pub async fn kmeans(&mut self, points: &[Vec<f64>], k: usize, iterations: usize) -> Result<Vec<Vec<f64>>> {
+            let v = self.call_raw("kmeans", &KmeansParams { points, k, iterations }).await?;
+            if !self.verify { return Ok(Default::default()); }
+            let arr = v.get("centroids").ok_or_else(|| anyhow::anyhow!("missing centroids"))?;
+            Ok(serde_json::from_value(arr.clone())?)
+        }
+        pub async fn stats(&mut self, values: &[f64]) -> Result<f64> {
+            let v = self.call_raw("stats", &ValuesParams { values }).await?;
+            if !self.verify { return Ok(Default::default()); }
+            v.get("mean").and_then(|x| x.as_f64()).ok_or_else(|| anyhow::anyhow!("missing mean"))
+        }
+        pub async fn rle(&mut self, data_base64: &str) -> Result<Vec<u8>> {
+            let v = self.call_raw("rle", &DataParams { data_base64 }).await?;
+            if !self.verify { return Ok(Default::default()); }
+            let s = v.get("encoded_base64").and_then(|x| x.as_str()).ok_or_else(|| anyhow::anyhow!("missing encoded_base64"))?;
+            Ok(B64.decode(s.as_bytes())?)
+        }
+    }
+}
+
+/// Synthetic request payloads, built once and shared by every request task so the
+/// generator doesn't spend its CPU on allocating and filling identical buffers. Binary
+/// payloads are kept base64-encoded, as they are sent.
+struct Payloads {
+    hash: String,
+    sort: Vec<i32>,
+    mat_n: usize,
+    mat_a: Vec<f64>,
+    mat_b: Vec<f64>,
+    compress: String,
+    kmeans: Vec<Vec<f64>>,
+    rle: String,
+    stats: Vec<f64>,
+}
+
+impl Payloads {
+    fn new() -> Self {
+        use base64::Engine as _;
+        use client_shim::B64;
+        let hash = B64.encode((0..256).map(|i| (i as u8).wrapping_mul(31).wrapping_add(7)).collect::<Vec<_>>());
+        let sort = (0..1000u64)
+            .map(|i| {
+                let x = ((i * 1_103_515_245u64 + 12_345u64) >> 8) as u32; // safe math
+                (x as i32) ^ 0x5a5a5a5a
+            })
+            .collect();
+        let mat_n = 16usize;
+        let mat_a = (0..mat_n * mat_n).map(|i| (i as f64).sin()).collect();
+        let mat_b = (0..mat_n * mat_n).map(|i| (i as f64).cos()).collect();
+        let compress = B64.encode((0..512).map(|i| (i as u8).wrapping_mul(17).wrapping_add(3)).collect::<Vec<_>>());
+        let kmeans = (0..256)
+            .map(|i| vec![(i % 4) as f64 * 10.0 + (i as f64).sin(), (i as f64).cos()])
+            .collect();
+        let rle = B64.encode((0..512).map(|i| (i / 16) as u8).collect::<Vec<_>>());
+        let stats = (0..1000).map(|i| (i as f64).sin() * 100.0).collect();
+        Self { hash, sort, mat_n, mat_a, mat_b, compress, kmeans, rle, stats }
+    }
+}
+
+/// Per-connection flow counters, to spot a pooled connection that is holding the run back.
+#[derive(Default)]
+struct ConnStats {
+    completed: AtomicU64,
+    /// Requests waiting for or holding this connection's lock
+    in_flight: AtomicU64,
+    max_in_flight: AtomicU64,
+}
+
+impl ConnStats {
+    /// Count a request as in flight until the returned guard drops.
+    fn begin(&self) -> InFlight<'_> {
+        let now = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_in_flight.fetch_max(now, Ordering::Relaxed);
+        InFlight(self)
+    }
+}
+
+struct InFlight<'a>(&'a ConnStats);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// One line per pooled connection.
+fn conn_stats_report(stats: &[Arc<ConnStats>]) -> String {
+    stats.iter().enumerate()
+        .map(|(i, s)| format!(
+            "conn {i}: completed={} max_in_flight={}\n",
+            s.completed.load(Ordering::Relaxed),
+            s.max_in_flight.load(Ordering::Relaxed),
+        ))
+        .collect()
+}
+
+/// Totals per server when the pool spans several; connection `i` went to `addrs[i % addrs.len()]`.
+fn addr_stats_report(addrs: &[String], stats: &[Arc<ConnStats>]) -> String {
+    addrs.iter().enumerate()
+        .map(|(a, addr)| {
+            let conns: Vec<_> = stats.iter().skip(a).step_by(addrs.len()).collect();
+            let completed: u64 = conns.iter().map(|s| s.completed.load(Ordering::Relaxed)).sum();
+            format!("addr {addr}: conns={} completed={completed}\n", conns.len())
+        })
+        .collect()
+}
+
+/// Open `pool_size` connections, the `i`th to `addrs[i % addrs.len()]`, all dialed at once so an
+/// unreachable server costs one timeout rather than one per connection. Returned in that order.
+async fn dial_pool(
+    addrs: &[String],
+    pool_size: usize,
+    verify: bool,
+    connect_timeout: Duration,
+) -> Result<Vec<client_shim::RpcClient>> {
+    let mut dials = tokio::task::JoinSet::new();
+    for i in 0..pool_size {
+        let addr = addrs[i % addrs.len()].clone();
+        dials.spawn(async move {
+            let conn = client_shim::RpcClient::connect(&addr, verify, connect_timeout).await;
+            (i, conn.map_err(|e| anyhow::anyhow!("connecting pool to {addr}: {e}")))
+        });
+    }
+    let mut pool: Vec<_> = std::iter::repeat_with(|| None).take(pool_size).collect();
+    while let Some(dialed) = dials.join_next().await {
+        let (i, conn) = dialed?;
+        pool[i] = Some(conn?);
+    }
+    Ok(pool.into_iter().flatten().collect())
+}
+
+/// Settings for `--connect-only`.
+#[derive(Clone, Copy)]
+struct ConnectOnly {
+    /// Complete a `hello` before disconnecting
+    handshake: bool,
+    verify: bool,
+    connect_timeout: Duration,
+}
+
+/// Open one connection to `addr` (and handshake, if asked), then close it.
+async fn connect_once(addr: &str, opts: ConnectOnly) -> Result<()> {
+    let mut c = client_shim::RpcClient::connect(addr, opts.verify, opts.connect_timeout).await?;
+    if opts.handshake {
+        c.hello().await?;
+    }
+    Ok(())
+}
+
+/// The `--connect-only` generator: at `rps`, connect to the next of `addrs` and disconnect,
+/// sending each setup latency (ms) to `tx`, until `end_time`. Failures are counted in `errors`.
+async fn run_connect_only(
+    addrs: &[String],
+    rps: u64,
+    end_time: Instant,
+    opts: ConnectOnly,
+    tx: mpsc::Sender<f64>,
+    errors: Arc<AtomicU64>,
+) {
+    let mut tick = interval(Duration::from_nanos(1_000_000_000 / rps.max(1)));
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut i = 0usize;
+    while Instant::now() < end_time {
+        tick.tick().await;
+        let addr = addrs[i % addrs.len()].clone();
+        i += 1;
+        let txc = tx.clone();
+        let errs = errors.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let res = connect_once(&addr, opts).await;
+            let _ = txc.send(start.elapsed().as_secs_f64() * 1000.0).await;
+            if let Err(e) = res {
+                errs.fetch_add(1, Ordering::Relaxed);
+                warn!("connect error: {e}");
+            }
+        });
+    }
+}
+
+/// Flags that take a value, as `--flag VALUE` or `--flag=VALUE`.
+const VALUED_FLAGS: [&str; 3] = ["--hgrm", "--p99-target-ms", "--max-error-rate"];
+
+fn flag_value<'a>(argv: &'a [String], flag: &str) -> Option<&'a str> {
+    let prefix = format!("{flag}=");
+    argv.iter().position(|a| a == flag).and_then(|i| argv.get(i + 1)).map(String::as_str)
+        .or_else(|| argv.iter().find_map(|a| a.strip_prefix(&prefix)))
+}
+
+/// Pass/fail thresholds checked once the run is over.
+#[derive(Default)]
+struct SloTargets {
+    p99_ms: Option<f64>,
+    /// Failed requests as a fraction of all requests
+    max_error_rate: Option<f64>,
+}
+
+/// A description of each SLO the run missed; empty when it met them all.
+fn slo_violations(targets: &SloTargets, p99_ms: f64, errors: u64, total: u64) -> Vec<String> {
+    let mut failed = Vec::new();
+    if let Some(target) = targets.p99_ms.filter(|&t| p99_ms > t) {
+        failed.push(format!("p99 {p99_ms:.3}ms exceeds target {target}ms"));
+    }
+    let error_rate = if total == 0 { 0.0 } else { errors as f64 / total as f64 };
+    if let Some(target) = targets.max_error_rate.filter(|&t| error_rate > t) {
+        failed.push(format!("error rate {error_rate:.4} ({errors}/{total}) exceeds target {target}"));
     }
+    failed
 }
 
 #[tokio::main]
@@ -107,45 +405,84 @@ async fn main() -> Result<()> {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let args: Vec<String> = env::args().collect();
-    let addr = args.get(1).map(|s| s.as_str()).unwrap_or("127.0.0.1:8080");
+    let no_verify = env::args().any(|a| a == "--no-verify");
+    let connect_only = env::args().any(|a| a == "--connect-only");
+    let connect_timeout = env::args()
+        .find_map(|a| a.strip_prefix("--tcp-connect-timeout=").and_then(|v| v.parse().ok()))
+        .map(Duration::from_millis)
+        .unwrap_or(simple_rpc_rust::DEFAULT_CONNECT_TIMEOUT);
+    let argv: Vec<String> = env::args().collect();
+    let hgrm: Option<std::path::PathBuf> = flag_value(&argv, "--hgrm").map(Into::into);
+    let parse_flag = |flag| flag_value(&argv, flag)
+        .map(|v| v.parse::<f64>().map_err(|e| anyhow::anyhow!("{flag} {v}: {e}")))
+        .transpose();
+    let slo = SloTargets { p99_ms: parse_flag("--p99-target-ms")?, max_error_rate: parse_flag("--max-error-rate")? };
+    // A valued flag's separate VALUE is not a positional arg
+    let args: Vec<&String> = argv.iter().enumerate()
+        .filter(|&(i, a)| !a.starts_with("--") && !VALUED_FLAGS.contains(&argv[i.saturating_sub(1)].as_str()))
+        .map(|(_, a)| a)
+        .collect();
+    let addrs: Vec<String> = args.get(1).map(|s| s.as_str()).unwrap_or("127.0.0.1:8080")
+        .split(',').map(str::trim).filter(|a| !a.is_empty()).map(String::from).collect();
+    anyhow::ensure!(!addrs.is_empty(), "no server address given");
     let rps: u64 = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(100);
     let duration_secs: u64 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(30);
-    let mode: String = args
-        .get(4)
-        .map(|s| s.as_str().to_owned())
-        .unwrap_or_else(|| "mix".to_owned());
+    let mode: Arc<str> = args.get(4).map(|s| s.as_str()).unwrap_or("mix").into();
 
-        info!("Loadgen addr={addr} rps={rps} duration={duration_secs}s");
+        info!("Loadgen addrs={} rps={rps} duration={duration_secs}s verify={} connect_only={connect_only}", addrs.join(","), !no_verify);
 
-    // small pool of persistent connections; round-robin each request
-    let pool_size = ((rps as f64).sqrt().ceil() as usize).max(4).min(64);
-    let mut pool = Vec::with_capacity(pool_size);
-    for _ in 0..pool_size {
-        pool.push(Arc::new(Mutex::new(client_shim::RpcClient::connect(addr).await?)));
-    }
+    // small pool of persistent connections, at least one per server; round-robin each request
+    let pool_size = if connect_only { 0 } else { ((rps as f64).sqrt().ceil() as usize).clamp(4, 64).max(addrs.len()) };
+    let pool: Vec<_> = dial_pool(&addrs, pool_size, !no_verify, connect_timeout).await?
+        .into_iter().map(|c| Arc::new(Mutex::new(c))).collect();
+    let conn_stats: Vec<Arc<ConnStats>> = (0..pool_size).map(|_| Default::default()).collect();
 
-    // collect latencies (ms)
-    let (tx, mut rx) = mpsc::unbounded_channel::<f64>();
+    // aggregate latencies (ms) as they arrive, streaming them to the CSV too
+    let (tx, rx) = mpsc::channel::<f64>(SAMPLE_QUEUE);
+    std::fs::create_dir_all("results")?;
+    let started = Instant::now();
+    let live = LatencySummary::shared(started)?;
+    let collector = tokio::spawn(collect_latencies(rx, "results/loadgen.csv".into(), CSV_FLUSH_EVERY, live.clone()));
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
 
     // open-loop ticker
     let mut tick = interval(Duration::from_nanos(1_000_000_000 / rps.max(1)));
     tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    let end_time = Instant::now() + Duration::from_secs(duration_secs);
+    let run_start = std::time::SystemTime::now();
+    let end_time = started + Duration::from_secs(duration_secs);
     let mut i = 0usize;
 
     // deterministic RNG for the op mix
     let rng = Arc::new(tokio::sync::Mutex::new(StdRng::seed_from_u64(0xC0FFEE)));
+    let payloads = Arc::new(Payloads::new());
+    let errors = Arc::new(AtomicU64::new(0));
 
-    while Instant::now() < end_time {
-        tick.tick().await;
+    if connect_only {
+        let opts = ConnectOnly { handshake: env::args().any(|a| a == "--handshake"), verify: !no_verify, connect_timeout };
+        tokio::select! {
+            _ = run_connect_only(&addrs, rps, end_time, opts, tx.clone(), errors.clone()) => {}
+            _ = &mut interrupted => warn!("interrupted: stopping early and reporting the samples so far"),
+        }
+    }
+    while !connect_only && Instant::now() < end_time {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = &mut interrupted => {
+                warn!("interrupted: stopping early and reporting the samples so far");
+                break;
+            }
+        }
 
         let cli = pool[i % pool_size].clone();
+        let stats = conn_stats[i % pool_size].clone();
         i += 1;
         let txc = tx.clone();
         let rngc = rng.clone();
         let mode_c = mode.clone();
+        let pl = payloads.clone();
+        let errs = errors.clone();
 
         tokio::spawn(async move {
     // choose operation (single-op mode overrides mix)
@@ -153,8 +490,8 @@ async fn main() -> Result<()> {
 let p: f64 = rng.gen();
 drop(rng);
 
-let which = if mode_c != "mix" {
-    mode_c.as_str()
+let which = if &*mode_c != "mix" {
+    &*mode_c
 } else {
     if p < 0.5 { "hash" }
     else if p < 0.7 { "sort" }
@@ -163,75 +500,521 @@ let which = if mode_c != "mix" {
 };
 
 let start = Instant::now();
+let _in_flight = stats.begin();
 let res: Result<()> = async {
     let mut c = cli.lock().await;
     match which {
-        "hash" => {
-            let mut data = vec![0u8; 256];
-            for (i, b) in data.iter_mut().enumerate() { *b = (i as u8).wrapping_mul(31).wrapping_add(7); }
-            let _ = c.hash_compute(&data).await?;
-        }
-        "sort" => {
-            let mut vals = vec![0i32; 1000];
-            for (i, v) in vals.iter_mut().enumerate() {
-                let x = ((i as u64 * 1_103_515_245u64 + 12_345u64) >> 8) as u32; // safe math
-                *v = (x as i32) ^ 0x5a5a5a5a;
-            }
-            let _ = c.sort_array(vals).await?;
-        }
-        "matmul" => {
-            let n = 16usize;
-            let mut a = vec![0.0f64; n*n];
-            let mut b = vec![0.0f64; n*n];
-            for i in 0..n*n { a[i] = (i as f64).sin(); b[i] = (i as f64).cos(); }
-            let _ = c.matrix_multiply(n, a, b).await?;
-        }
-        "compress" => {
-            let mut data = vec![0u8; 512];
-            for (i, b) in data.iter_mut().enumerate() { *b = (i as u8).wrapping_mul(17).wrapping_add(3); }
-            let _ = c.compress_data("zlib", &data).await?;
-        }
+        "hash" => { let _ = c.hash_compute(&pl.hash).await?; }
+        "sort" => { let _ = c.sort_array(&pl.sort).await?; }
+        "matmul" => { let _ = c.matrix_multiply(pl.mat_n, &pl.mat_a, &pl.mat_b).await?; }
+        "compress" => { let _ = c.compress_data("zlib", &pl.compress).await?; }
+        "kmeans" => { let _ = c.kmeans(&pl.kmeans, 4, 10).await?; }
+        "rle" => { let _ = c.rle(&pl.rle).await?; }
+        "stats" => { let _ = c.stats(&pl.stats).await?; }
         _ => unreachable!(),
     }
     Ok(())
 }.await;
 
     let elapsed = start.elapsed().as_secs_f64() * 1000.0;
-    let _ = txc.send(elapsed);
+    let _ = txc.send(elapsed).await;
     if let Err(e) = res {
+        errs.fetch_add(1, Ordering::Relaxed);
         warn!("request error: {e}");
     }
 });
     }
 
     drop(tx);
-    let mut lats = Vec::<f64>::new();
-    while let Some(ms) = rx.recv().await { lats.push(ms); }
-    lats.sort_by(|a,b| a.partial_cmp(b).unwrap());
+    collector.await??;
+    let mut lats = live.lock().unwrap();
+    lats.throughput.pad_to(started.elapsed().as_secs().min(duration_secs) as usize);
+    print!("{}", conn_stats_report(&conn_stats));
+    if addrs.len() > 1 && !connect_only {
+        print!("{}", addr_stats_report(&addrs, &conn_stats));
+    }
 
-    if lats.is_empty() {
+    if lats.hist.is_empty() {
         println!("No samples collected.");
+        anyhow::ensure!(slo.p99_ms.is_none() && slo.max_error_rate.is_none(), "no samples to check the SLOs against");
         return Ok(());
     }
 
-    fn pct(v: &Vec<f64>, p: f64) -> f64 {
-        let n = v.len();
-        let idx = ((p/100.0) * (n as f64 - 1.0)).round() as usize;
-        v[idx]
+    let avg = lats.sum_ms / lats.hist.len() as f64;
+    let p50 = lats.percentile_ms(50.0);
+    let p95 = lats.percentile_ms(95.0);
+    let p99 = lats.percentile_ms(99.0);
+
+    println!("samples={}, avg_ms={:.3}, p50={:.3}, p95={:.3}, p99={:.3}", lats.hist.len(), avg, p50, p95, p99);
+    let throughput = lats.throughput.to_json();
+    println!("throughput_per_sec: min={}, max={}, avg={:.1}", throughput["min"], throughput["max"], throughput["avg"].as_f64().unwrap_or(0.0));
+    println!("Wrote results/loadgen.csv");
+    let summary = serde_json::json!({
+        "samples": lats.hist.len(),
+        "errors": errors.load(Ordering::Relaxed),
+        "latency_ms": { "avg": avg, "p50": p50, "p95": p95, "p99": p99 },
+        "throughput": throughput,
+    });
+    std::fs::write("results/loadgen.json", serde_json::to_string_pretty(&summary)?)?;
+    println!("Wrote results/loadgen.json");
+    if let Some(path) = hgrm {
+        write_hgrm(&path, &lats, run_start)?;
+        println!("Wrote {}", path.display());
     }
-    let sum: f64 = lats.iter().copied().sum();
-    let avg = sum / lats.len() as f64;
-    let p50 = pct(&lats, 50.0);
-    let p95 = pct(&lats, 95.0);
-    let p99 = pct(&lats, 99.0);
 
-    println!("samples={}, avg_ms={:.3}, p50={:.3}, p95={:.3}, p99={:.3}", lats.len(), avg, p50, p95, p99);
+    let failed = slo_violations(&slo, p99, errors.load(Ordering::Relaxed), lats.hist.len());
+    for f in &failed {
+        println!("SLO FAILED: {f}");
+    }
+    anyhow::ensure!(failed.is_empty(), "{} SLO(s) violated", failed.len());
+    Ok(())
+}
 
-    std::fs::create_dir_all("results")?;
-    let mut f = std::fs::File::create("results/loadgen.csv")?;
+/// Export `lats` as a one-interval HdrHistogram log readable by `HistogramLogProcessor` and
+/// friends, in milliseconds. The percentile distribution is written as comments ahead of it.
+fn write_hgrm(path: &std::path::Path, lats: &LatencySummary, run_start: std::time::SystemTime) -> Result<()> {
+    use hdrhistogram::serialization::{interval_log::IntervalLogWriterBuilder, V2DeflateSerializer};
+    let mut table = format!("{:>12} {:>14} {:>10} {:>14}\n\n", "Value", "Percentile", "TotalCount", "1/(1-Percentile)");
+    let mut total = 0;
+    for v in lats.hist.iter_quantiles(1) {
+        total += v.count_since_last_iteration();
+        let q = v.quantile_iterated_to();
+        let inverse = if q < 1.0 { format!("{:.2}", 1.0 / (1.0 - q)) } else { "inf".into() };
+        table += &format!("{:>12.3} {:>14.12} {:>10} {:>14}\n", v.value_iterated_to() as f64 / 1000.0, q, total, inverse);
+    }
+    table += &format!("#[Mean = {:.3}, StdDeviation = {:.3}]\n", lats.hist.mean() / 1000.0, lats.hist.stdev() / 1000.0);
+    table += &format!("#[Max = {:.3}, Total count = {}]", lats.hist.max() as f64 / 1000.0, lats.hist.len());
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut serializer = V2DeflateSerializer::new();
+    let mut log = IntervalLogWriterBuilder::new()
+        .with_start_time(run_start)
+        .with_base_time(run_start)
+        .with_max_value_divisor(1000.0)
+        .begin_log_with(&mut out, &mut serializer)?;
+    log.write_comment(&table)?;
+    let elapsed = run_start.elapsed().unwrap_or_default();
+    log.write_histogram(&lats.hist, Duration::ZERO, elapsed, None)
+        .map_err(|e| anyhow::anyhow!("writing {}: {e:?}", path.display()))?;
+    drop(log);
+    std::io::Write::flush(&mut out)?;
+    Ok(())
+}
+
+/// Samples between CSV flushes; bounds what a crash loses and keeps the file current.
+const CSV_FLUSH_EVERY: usize = 4096;
+
+/// Latencies queued for the collector before finished requests wait for it.
+const SAMPLE_QUEUE: usize = 4096;
+
+/// What the end-of-run summary needs; individual samples live only in the CSV.
+struct LatencySummary {
+    /// Latencies in microseconds
+    hist: hdrhistogram::Histogram<u64>,
+    sum_ms: f64,
+    throughput: ThroughputSeries,
+    /// Throughput buckets count from here
+    run_start: Instant,
+}
+
+/// The summary as the collector builds it, readable at any point of the run.
+type LiveSummary = Arc<std::sync::Mutex<LatencySummary>>;
+
+/// Completed requests in each whole second of the run, by completion time.
+#[derive(Default)]
+struct ThroughputSeries {
+    per_sec: Vec<u64>,
+}
+
+impl ThroughputSeries {
+    fn record(&mut self, since_start: Duration) {
+        let sec = since_start.as_secs() as usize;
+        if self.per_sec.len() <= sec {
+            self.per_sec.resize(sec + 1, 0);
+        }
+        self.per_sec[sec] += 1;
+    }
+
+    /// Extend to at least `secs` buckets, so seconds where nothing completed count as zero.
+    fn pad_to(&mut self, secs: usize) {
+        if self.per_sec.len() < secs {
+            self.per_sec.resize(secs, 0);
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let n = self.per_sec.len().max(1) as f64;
+        serde_json::json!({
+            "per_second": self.per_sec,
+            "min": self.per_sec.iter().min().copied().unwrap_or(0),
+            "max": self.per_sec.iter().max().copied().unwrap_or(0),
+            "avg": self.per_sec.iter().sum::<u64>() as f64 / n,
+        })
+    }
+}
+
+impl LatencySummary {
+    fn shared(run_start: Instant) -> Result<LiveSummary> {
+        // 1µs to one hour at 3 significant figures
+        let hist = hdrhistogram::Histogram::new_with_bounds(1, 3_600_000_000, 3)?;
+        Ok(Arc::new(std::sync::Mutex::new(Self { hist, sum_ms: 0.0, throughput: Default::default(), run_start })))
+    }
+
+    fn record(&mut self, ms: f64) {
+        self.throughput.record(self.run_start.elapsed());
+        self.hist.saturating_record((ms * 1000.0) as u64);
+        self.sum_ms += ms;
+    }
+
+    /// Nearest-rank percentile for `p` in 0..=100, to the histogram's 3 significant figures:
+    /// p0 is the smallest sample and p100 the largest, for any sample count.
+    fn percentile_ms(&self, p: f64) -> f64 {
+        self.hist.value_at_percentile(p) as f64 / 1000.0
+    }
+}
+
+/// Append each latency to the CSV at `path` (flushed every `flush_every` samples) and fold it
+/// into `summary` as it arrives, so memory stays flat however long the run and a run cut
+/// short still has everything that completed.
+async fn collect_latencies(
+    mut rx: mpsc::Receiver<f64>,
+    path: std::path::PathBuf,
+    flush_every: usize,
+    summary: LiveSummary,
+) -> Result<()> {
     use std::io::Write;
-    writeln!(f, "latency_ms")?;
-    for x in &lats { writeln!(f, "{:.6}", x)?; }
-    println!("Wrote results/loadgen.csv");
+    let mut csv = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    writeln!(csv, "latency_ms")?;
+    csv.flush()?;
+    let mut unflushed = 0;
+    while let Some(ms) = rx.recv().await {
+        writeln!(csv, "{:.6}", ms)?;
+        summary.lock().unwrap().record(ms);
+        unflushed += 1;
+        if unflushed >= flush_every {
+            csv.flush()?;
+            unflushed = 0;
+        }
+    }
+    csv.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::client_shim::{Duration, RpcClient};
+    use super::{
+        addr_stats_report, collect_latencies, conn_stats_report, dial_pool, flag_value, run_connect_only, slo_violations,
+        write_hgrm, ConnStats, ConnectOnly, LatencySummary, Payloads, SloTargets, ThroughputSeries,
+    };
+    use super::client_shim::{encode_request, Frame};
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use simple_rpc_rust::{read_frame, resp_ok, write_frame, RpcRequest};
+    use tokio::net::TcpListener;
+
+    /// Server that answers every request with a result no wrapper can decode.
+    async fn opaque_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    while let Ok(v) = read_frame(&mut sock).await {
+                        let req: RpcRequest = serde_json::from_value(v).unwrap();
+                        let resp = resp_ok(&req.request_id, serde_json::json!("opaque"));
+                        write_frame(&mut sock, &resp).await.unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_no_verify_skips_decoding() {
+        let addr = opaque_server().await;
+        let mut c = RpcClient::connect(&addr, false, Duration::from_secs(1)).await.unwrap();
+        c.compress_data("zlib", "YWJj").await.unwrap();
+        c.sort_array(&[2, 1]).await.unwrap();
+        c.matrix_multiply(1, &[1.0], &[1.0]).await.unwrap();
+
+        let mut c = RpcClient::connect(&addr, true, Duration::from_secs(1)).await.unwrap();
+        assert!(c.compress_data("zlib", "YWJj").await.is_err());
+    }
+
+    /// Server answering `sort_array` properly, counting the requests it gets.
+    async fn counting_server() -> (String, Arc<std::sync::atomic::AtomicU64>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let seen = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let counter = seen.clone();
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    while let Ok(v) = read_frame(&mut sock).await {
+                        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let req: RpcRequest = serde_json::from_value(v).unwrap();
+                        let resp = resp_ok(&req.request_id, serde_json::json!({ "values": [1, 2] }));
+                        write_frame(&mut sock, &resp).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, seen)
+    }
+
+    #[tokio::test]
+    async fn test_pool_spreads_across_addresses() {
+        let (a, seen_a) = counting_server().await;
+        let (b, seen_b) = counting_server().await;
+        let addrs = vec![a.clone(), b.clone()];
+        let mut pool = dial_pool(&addrs, 5, true, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(pool.len(), 5);
+        for (i, conn) in pool.iter_mut().enumerate() {
+            conn.sort_array(&[2, 1]).await.unwrap();
+            // Dial order is preserved: even connections went to `a`
+            let want = if i % 2 == 0 { &a } else { &b };
+            assert_eq!(&conn.peer_addr().to_string(), want);
+        }
+        use std::sync::atomic::Ordering::Relaxed;
+        assert_eq!((seen_a.load(Relaxed), seen_b.load(Relaxed)), (3, 2));
+
+        let stats: Vec<Arc<ConnStats>> = (0..5).map(|_| Default::default()).collect();
+        for s in &stats { drop(s.begin()); }
+        assert_eq!(addr_stats_report(&addrs, &stats), format!("addr {a}: conns=3 completed=3\naddr {b}: conns=2 completed=2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_only_records_setup_latencies() {
+        use simple_rpc_rust::server::{serve_listener, ServerConfig};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve_listener(listener, ServerConfig::default()));
+
+        let path = std::env::temp_dir().join(format!("loadgen-{}.csv", uuid::Uuid::new_v4()));
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let live = LatencySummary::shared(std::time::Instant::now()).unwrap();
+        let collector = tokio::spawn(collect_latencies(rx, path.clone(), 64, live.clone()));
+        let errors = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let opts = ConnectOnly { handshake: true, verify: true, connect_timeout: Duration::from_secs(1) };
+        let end = std::time::Instant::now() + Duration::from_millis(200);
+        run_connect_only(&[addr], 100, end, opts, tx, errors.clone()).await;
+        collector.await.unwrap().unwrap();
+
+        let summary = live.lock().unwrap();
+        assert!(summary.hist.len() >= 10, "{} connections", summary.hist.len());
+        assert_eq!(errors.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert!(summary.percentile_ms(100.0) > 0.0);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    thread_local! {
+        /// Times `encode_request` on this thread had to grow its buffer, i.e. missed the pool
+        pub(crate) static SCRATCH_GROWS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[tokio::test]
+    async fn test_requests_reuse_scratch_buffers() {
+        let (addr, seen) = counting_server().await;
+        let mut c = RpcClient::connect(&addr, true, Duration::from_secs(1)).await.unwrap();
+        let pl = Payloads::new();
+        let before = SCRATCH_GROWS.with(Cell::get);
+        for _ in 0..100 {
+            c.sort_array(&pl.sort).await.unwrap();
+        }
+        // Only the first request on this thread allocates a frame buffer
+        assert!(SCRATCH_GROWS.with(Cell::get) - before <= 1);
+        assert_eq!(seen.load(std::sync::atomic::Ordering::Relaxed), 100);
+
+        // What goes out is an ordinary frame and request, with a fresh id each time
+        let params = serde_json::json!({ "values": [2, 1] });
+        let decode = |frame: Frame| async move {
+            serde_json::from_value::<RpcRequest>(read_frame(frame.as_ref()).await.unwrap()).unwrap()
+        };
+        let a = decode(encode_request("sort_array", &params).unwrap()).await;
+        let b = decode(encode_request("sort_array", &params).unwrap()).await;
+        assert_eq!((a.func.as_str(), &a.params), ("sort_array", &params));
+        assert_ne!(a.request_id, b.request_id);
+    }
+
+    #[test]
+    fn test_conn_stats_reported_per_connection() {
+        let stats: Vec<Arc<ConnStats>> = (0..3).map(|_| Default::default()).collect();
+        {
+            // conn 0: two overlapping requests; conn 1: one; conn 2: idle
+            let _a = stats[0].begin();
+            let _b = stats[0].begin();
+        }
+        drop(stats[1].begin());
+
+        let report = conn_stats_report(&stats);
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines, [
+            "conn 0: completed=2 max_in_flight=2",
+            "conn 1: completed=1 max_in_flight=1",
+            "conn 2: completed=0 max_in_flight=0",
+        ]);
+        assert_eq!(stats[0].in_flight.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_slo_violations() {
+        let slo = SloTargets { p99_ms: Some(10.0), max_error_rate: Some(0.01) };
+        assert!(slo_violations(&slo, 9.5, 1, 1000).is_empty());
+        let failed = slo_violations(&slo, 12.0, 20, 1000);
+        assert_eq!(failed.len(), 2);
+        assert!(failed[0].starts_with("p99 12.000ms exceeds target 10ms"));
+        assert!(failed[1].starts_with("error rate 0.0200 (20/1000)"));
+        assert!(slo_violations(&SloTargets::default(), 1e9, 1000, 1000).is_empty());
+
+        let argv: Vec<String> = ["loadgen", "--p99-target-ms", "5", "--max-error-rate=0.5"].map(String::from).into();
+        assert_eq!(flag_value(&argv, "--p99-target-ms"), Some("5"));
+        assert_eq!(flag_value(&argv, "--max-error-rate"), Some("0.5"));
+        assert_eq!(flag_value(&argv, "--hgrm"), None);
+    }
+
+    #[test]
+    fn test_throughput_series_buckets_per_second() {
+        // A four-second run that sags in its third second and has nothing complete in the fourth
+        let mut series = ThroughputSeries::default();
+        for (sec, n) in [(0u64, 10u64), (1, 12), (2, 3)] {
+            for i in 0..n {
+                series.record(Duration::from_secs(sec) + Duration::from_millis(i * 1000 / n));
+            }
+        }
+        series.pad_to(4);
+        let out = series.to_json();
+        assert_eq!(out["per_second"], serde_json::json!([10, 12, 3, 0]));
+        assert_eq!(out["min"], 0);
+        assert_eq!(out["max"], 12);
+        assert_eq!(out["avg"], 6.25);
+    }
+
+    #[tokio::test]
+    async fn test_collector_buckets_completions_by_second() {
+        let path = std::env::temp_dir().join(format!("loadgen-{}.csv", uuid::Uuid::new_v4()));
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        // Pretend the run began 2.5s ago: these land in the third bucket
+        let start = std::time::Instant::now() - Duration::from_millis(2500);
+        let live = LatencySummary::shared(start).unwrap();
+        let collector = tokio::spawn(collect_latencies(rx, path.clone(), 64, live.clone()));
+        for ms in [1.0, 2.0] { tx.send(ms).await.unwrap(); }
+        drop(tx);
+        collector.await.unwrap().unwrap();
+        let mut summary = live.lock().unwrap();
+        summary.throughput.pad_to(3);
+        assert_eq!(summary.throughput.to_json()["per_second"], serde_json::json!([0, 0, 2]));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_percentiles_on_tiny_samples() {
+        async fn summarize(ms: &[f64]) -> LatencySummary {
+            let path = std::env::temp_dir().join(format!("loadgen-{}.csv", uuid::Uuid::new_v4()));
+            let (tx, rx) = tokio::sync::mpsc::channel(ms.len());
+            for &v in ms { tx.send(v).await.unwrap(); }
+            drop(tx);
+            let live = LatencySummary::shared(std::time::Instant::now()).unwrap();
+            collect_latencies(rx, path.clone(), 64, live.clone()).await.unwrap();
+            let _ = std::fs::remove_file(&path);
+            Arc::into_inner(live).unwrap().into_inner().unwrap()
+        }
+        let close = |a: f64, b: f64| (a - b).abs() < 0.01;
+
+        let one = summarize(&[7.0]).await;
+        for p in [0.0, 50.0, 99.0, 100.0] {
+            assert!(close(one.percentile_ms(p), 7.0), "p{p} of one sample = {}", one.percentile_ms(p));
+        }
+
+        // Nearest rank: p0 is the minimum, p50 the lower of two, p100 exactly the maximum
+        let two = summarize(&[2.0, 1.0]).await;
+        assert!(close(two.percentile_ms(0.0), 1.0));
+        assert!(close(two.percentile_ms(50.0), 1.0));
+        assert!(close(two.percentile_ms(100.0), 2.0));
+
+        let five = summarize(&[5.0, 1.0, 4.0, 2.0, 3.0]).await;
+        assert!(close(five.percentile_ms(0.0), 1.0));
+        assert!(close(five.percentile_ms(50.0), 3.0));
+        assert!(close(five.percentile_ms(100.0), 5.0));
+    }
+
+    #[tokio::test]
+    async fn test_csv_grows_during_run() {
+        let path = std::env::temp_dir().join(format!("loadgen-{}.csv", uuid::Uuid::new_v4()));
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let live = LatencySummary::shared(std::time::Instant::now()).unwrap();
+        let collector = tokio::spawn(collect_latencies(rx, path.clone(), 2, live.clone()));
+        let lines = || async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            std::fs::read_to_string(&path).unwrap_or_default().lines().count()
+        };
+
+        for ms in [1.0, 2.0] { tx.send(ms).await.unwrap(); }
+        let early = lines().await;
+        assert_eq!(early, 3, "header plus the first flushed batch");
+        for ms in [3.0, 4.0, 5.0, 6.0] { tx.send(ms).await.unwrap(); }
+        assert_eq!(lines().await, 7);
+
+        tx.send(100.0).await.unwrap();
+        drop(tx);
+        collector.await.unwrap().unwrap();
+        let summary = live.lock().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 8);
+        assert_eq!(summary.hist.len(), 7);
+        assert_eq!(summary.sum_ms, 121.0);
+        assert!((summary.percentile_ms(50.0) - 4.0).abs() < 0.01);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_samples_aggregated_while_generating() {
+        let path = std::env::temp_dir().join(format!("loadgen-{}.csv", uuid::Uuid::new_v4()));
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let live = LatencySummary::shared(std::time::Instant::now()).unwrap();
+        let collector = tokio::spawn(collect_latencies(rx, path.clone(), 64, live.clone()));
+
+        // More samples than the queue holds, with the sender (the generator) still open
+        for i in 0..10 { tx.send(i as f64 + 1.0).await.unwrap(); }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        {
+            let partial = live.lock().unwrap();
+            assert_eq!(partial.hist.len(), 10);
+            assert_eq!(partial.sum_ms, 55.0);
+        }
+        assert!(!collector.is_finished());
+
+        drop(tx);
+        collector.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_hgrm_export_parses_back() {
+        use hdrhistogram::serialization::{interval_log::{IntervalLogIterator, LogEntry}, Deserializer};
+        let csv = std::env::temp_dir().join(format!("loadgen-{}.csv", uuid::Uuid::new_v4()));
+        let hgrm = csv.with_extension("hgrm");
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let live = LatencySummary::shared(std::time::Instant::now()).unwrap();
+        let collector = tokio::spawn(collect_latencies(rx, csv.clone(), 64, live.clone()));
+        for i in 0..500 { tx.send(0.5 + i as f64 / 100.0).await.unwrap(); }
+        drop(tx);
+        collector.await.unwrap().unwrap();
+        let summary = live.lock().unwrap();
+
+        write_hgrm(&hgrm, &summary, std::time::SystemTime::now()).unwrap();
+        let text = std::fs::read(&hgrm).unwrap();
+        assert!(String::from_utf8_lossy(&text).contains("Total count = 500"));
+        let intervals: Vec<_> = IntervalLogIterator::new(&text)
+            .filter_map(|e| match e.unwrap() { LogEntry::Interval(h) => Some(h), _ => None })
+            .collect();
+        assert_eq!(intervals.len(), 1);
+        let bytes = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, intervals[0].encoded_histogram()).unwrap();
+        let parsed: hdrhistogram::Histogram<u64> = Deserializer::new().deserialize(&mut &bytes[..]).unwrap();
+        assert_eq!(parsed.len(), summary.hist.len());
+        assert_eq!(parsed.value_at_quantile(0.99), summary.hist.value_at_quantile(0.99));
+        let _ = std::fs::remove_file(&csv);
+        let _ = std::fs::remove_file(&hgrm);
+    }
+}
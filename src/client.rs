@@ -0,0 +1,1477 @@
+//! Async client: one connection, many concurrent calls matched to responses by request_id.
+
+use anyhow::{Result, anyhow};
+use futures_util::{Stream, StreamExt};
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+use serde::Serialize;
+use serde_json::json;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, sync::{mpsc, watch, Mutex, Notify}, task::AbortHandle};
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+use tracing::{info, warn};
+use uuid::Uuid;
+use crate::{Clock, TokioClock, ClientError, RpcRequest, RpcResponse, Transport, read_response_frame, write_frame, tcp_connect, DEFAULT_CONNECT_TIMEOUT, PROTOCOL_VERSION};
+
+/// Which of a call's response frames the reader passes on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    /// Only the terminal `Completed`/`Error`; callers that want nothing else never queue acks or chunks
+    Terminal,
+    /// `Chunk`s too, ahead of the terminal response
+    Chunks,
+    /// Whatever frame arrives first, unexamined, which ends the call (`fast_call`)
+    First,
+}
+
+/// Where the reader delivers one call's responses.
+struct Route {
+    tx: mpsc::Sender<RpcResponse>,
+    delivery: Delivery,
+    /// Order the call was sent in, so the oldest can be found
+    seq: u64,
+    /// Chunks received so far, against `RpcClient::max_stream_chunks`
+    chunks: u64,
+    max_chunks: u64,
+}
+
+type PendingMap = Arc<Mutex<HashMap<String, Route>>>;
+
+/// What the client does once more calls are pending than `with_max_pending` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingOverflow {
+    /// Log a warning and keep every call.
+    Warn,
+    /// Log a warning and fail the oldest pending calls until back at the cap.
+    FailOldest,
+}
+
+/// Soft cap on the pending map; see `with_max_pending`.
+struct PendingLimit {
+    max: usize,
+    overflow: PendingOverflow,
+}
+
+impl PendingLimit {
+    /// Called with the map locked, right after a call was added to it.
+    fn enforce(&self, pending: &mut HashMap<String, Route>) {
+        if pending.len() <= self.max {
+            return;
+        }
+        if self.overflow == PendingOverflow::Warn {
+            // Once per crossing, not once per call while above the cap
+            if pending.len() == self.max + 1 {
+                warn!("{} calls pending, more than the cap of {}; are responses being lost?", pending.len(), self.max);
+            }
+            return;
+        }
+        while pending.len() > self.max {
+            let Some(oldest) = pending.iter().min_by_key(|(_, r)| r.seq).map(|(id, _)| id.clone()) else { break };
+            let route = pending.remove(&oldest).expect("key just found");
+            warn!("{} calls pending, more than the cap of {}; failing the oldest, {oldest}", pending.len() + 1, self.max);
+            let _ = route.tx.try_send(RpcResponse::Error {
+                request_id: oldest, ok: false, error: format!("evicted: more than {} calls pending", self.max), meta: HashMap::new(),
+            });
+        }
+    }
+}
+
+/// State of the client's circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through; failures are being counted.
+    Closed,
+    /// Too many recent failures: calls fail fast with `ClientError::CircuitOpen` until the cooldown ends.
+    Open,
+    /// Cooldown over: the next call is let through as a probe; its outcome closes or reopens the circuit.
+    HalfOpen,
+}
+
+/// Opens after `threshold` consecutive failures within `window`, stays open for `cooldown`,
+/// then admits one probe at a time until a call succeeds.
+struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    clock: Arc<dyn Clock>,
+    inner: std::sync::Mutex<BreakerInner>,
+}
+
+#[derive(Default)]
+struct BreakerInner {
+    /// Consecutive failures, counted from `first_failure`
+    failures: u32,
+    first_failure: Option<Instant>,
+    opened_at: Option<Instant>,
+    /// When the current half-open probe was let through; a probe that never reports back
+    /// (e.g. an abandoned page stream) stops blocking others after another cooldown
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, window: Duration, cooldown: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self { threshold: threshold.max(1), window, cooldown, clock, inner: Default::default() }
+    }
+
+    fn state(&self) -> BreakerState {
+        let now = self.clock.now();
+        match self.inner.lock().unwrap().opened_at {
+            None => BreakerState::Closed,
+            Some(t) if now.duration_since(t) < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether a call may be sent now.
+    fn admit(&self) -> Result<(), ClientError> {
+        let now = self.clock.now();
+        let mut b = self.inner.lock().unwrap();
+        let Some(opened_at) = b.opened_at else { return Ok(()) };
+        if now.duration_since(opened_at) < self.cooldown {
+            return Err(ClientError::CircuitOpen);
+        }
+        match b.probe_started {
+            Some(t) if now.duration_since(t) < self.cooldown => Err(ClientError::CircuitOpen),
+            _ => {
+                b.probe_started = Some(now);
+                Ok(())
+            }
+        }
+    }
+
+    fn record(&self, ok: bool) {
+        let mut b = self.inner.lock().unwrap();
+        if ok {
+            *b = BreakerInner::default();
+            return;
+        }
+        let now = self.clock.now();
+        if b.opened_at.is_some() {
+            // A failed probe (or a call sent before the circuit opened) restarts the cooldown
+            b.opened_at = Some(now);
+            b.probe_started = None;
+            return;
+        }
+        match b.first_failure {
+            Some(t) if now.duration_since(t) <= self.window => b.failures += 1,
+            _ => {
+                b.first_failure = Some(now);
+                b.failures = 1;
+            }
+        }
+        if b.failures >= self.threshold {
+            b.opened_at = Some(now);
+        }
+    }
+
+    /// Any answer shows the server is up except a `busy:` refusal. Errors the caller caused
+    /// (bad params, an unknown or denied function) must not open the circuit for other calls.
+    fn record_response(&self, resp: &RpcResponse) {
+        self.record(!is_busy(resp));
+    }
+}
+
+/// Settings for `with_retries`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryConfig {
+    /// Retries of one call after its first attempt
+    pub max_retries: u32,
+    /// Wait before each retry
+    pub backoff: Duration,
+    /// Retries the whole client may make in a burst; each spends one token
+    pub budget: u32,
+    /// Tokens returned to the budget per second, up to `budget`
+    pub refill_per_sec: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self { max_retries: 3, backoff: Duration::from_millis(50), budget: 10, refill_per_sec: 1.0 }
+    }
+}
+
+/// Client-wide token bucket that every retry draws from, so retries across all calls stay
+/// bounded however many calls are failing at once.
+struct RetryBudget {
+    cfg: RetryConfig,
+    clock: Arc<dyn Clock>,
+    /// Tokens left as of the `Instant`
+    inner: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl RetryBudget {
+    fn new(cfg: RetryConfig, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        Self { cfg, clock, inner: std::sync::Mutex::new((cfg.budget as f64, now)) }
+    }
+
+    /// Spend a token if one is left.
+    fn try_spend(&self) -> bool {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        let (tokens, last) = *inner;
+        let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.cfg.refill_per_sec)
+            .min(self.cfg.budget as f64);
+        let spent = tokens >= 1.0;
+        *inner = (if spent { tokens - 1.0 } else { tokens }, now);
+        spent
+    }
+}
+
+/// Whether the server refused the request without running it, so sending it again is safe.
+fn is_busy(resp: &RpcResponse) -> bool {
+    matches!(resp, RpcResponse::Error { error, .. } if error.starts_with("busy:"))
+}
+
+/// Body bytes sent per `body_append` by `call_streaming_body`.
+pub const BODY_CHUNK_BYTES: usize = 256 * 1024;
+
+/// Default for `with_max_queued_chunks`.
+pub const DEFAULT_MAX_QUEUED_CHUNKS: usize = 1024;
+
+/// Defaults for `with_max_stream`.
+pub const DEFAULT_MAX_STREAM_CHUNKS: u64 = 1 << 20;
+pub const DEFAULT_MAX_STREAM_BYTES: usize = 256 * 1024 * 1024;
+
+pub struct RpcClient {
+    writer: Arc<Mutex<Box<dyn AsyncWrite + Unpin + Send>>>,
+    pending: PendingMap,
+    /// True once the `hello` handshake succeeded; false again when the connection drops
+    ready: watch::Receiver<bool>,
+    ready_tx: watch::Sender<bool>,
+    /// Signalled whenever the last pending call finishes
+    idle: Arc<Notify>,
+    /// Set by `shutdown`; new calls are refused
+    closing: AtomicBool,
+    reader: AbortHandle,
+    /// Fields merged into every request's params unless the call sets them itself
+    default_params: serde_json::Map<String, serde_json::Value>,
+    /// Unconsumed chunks one streaming call may buffer before it is failed
+    max_queued_chunks: usize,
+    /// Most chunks, and reassembled bytes, one streaming call accepts in total
+    max_stream_chunks: u64,
+    max_stream_bytes: usize,
+    /// Per-call timeouts by function, then for everything else; see `with_timeouts`
+    func_timeouts: HashMap<String, Duration>,
+    default_timeout: Option<Duration>,
+    max_pending: Option<PendingLimit>,
+    /// Next `Route::seq`
+    next_seq: AtomicU64,
+    breaker: Option<Arc<CircuitBreaker>>,
+    retries: Option<Arc<RetryBudget>>,
+    /// Time source for the circuit breaker and retry budget
+    clock: Arc<dyn Clock>,
+    /// Why the server closed the connection, if it said
+    goodbye: Arc<std::sync::Mutex<Option<String>>>,
+}
+
+impl RpcClient {
+    /// Connect and complete the `hello` handshake; the client is ready for calls on return.
+    pub async fn connect(addr: &str) -> Result<Self> {
+        Self::connect_timeout(addr, DEFAULT_CONNECT_TIMEOUT).await
+    }
+
+    /// Like `connect`, but fails once establishing the TCP connection takes longer than `timeout`.
+    pub async fn connect_timeout(addr: &str, timeout: Duration) -> Result<Self> {
+        Self::over(tcp_connect(addr, timeout).await?).await
+    }
+
+    /// Complete the `hello` handshake over an already-open connection of any [`Transport`],
+    /// e.g. a Unix socket or an in-memory duplex.
+    pub async fn over<T: Transport>(conn: T) -> Result<Self> {
+        // Separate halves so the reader task never holds up writers
+        let (mut reader, writer) = conn.split();
+        let writer: Arc<Mutex<Box<dyn AsyncWrite + Unpin + Send>>> = Arc::new(Mutex::new(Box::new(writer)));
+        let pending: PendingMap = Arc::new(Mutex::new(HashMap::new()));
+
+        let (ready_tx, ready) = watch::channel(false);
+
+        let idle = Arc::new(Notify::new());
+        let pending_clone = pending.clone();
+        let reader_ready = ready_tx.clone();
+        let reader_idle = idle.clone();
+        let goodbye: Arc<std::sync::Mutex<Option<String>>> = Default::default();
+        let reader_goodbye = goodbye.clone();
+        let reader = tokio::spawn(async move {
+            loop {
+                let frame = match read_response_frame(&mut reader).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        let error = match reader_goodbye.lock().unwrap().as_deref() {
+                            Some(reason) => ClientError::ServerGoodbye(reason.to_string()).to_string(),
+                            None => {
+                                warn!("reader loop ended: {e}");
+                                "connection closed".to_string()
+                            }
+                        };
+                        let _ = reader_ready.send(false);
+                        let mut p = pending_clone.lock().await;
+                        // Tag each failure with its own request so callers can log which call was lost
+                        for (request_id, route) in p.drain() {
+                            let _ = route.tx.try_send(RpcResponse::Error {
+                                request_id, ok: false, error: error.clone(), meta: HashMap::new(),
+                            });
+                        }
+                        reader_idle.notify_waiters();
+                        break;
+                    }
+                };
+                let resp: RpcResponse = match serde_json::from_value(frame) {
+                    Ok(x) => x,
+                    Err(e) => { warn!("bad response json: {e}"); continue; }
+                };
+                if let RpcResponse::Goodbye { reason } = resp {
+                    info!("server is closing the connection: {reason}");
+                    *reader_goodbye.lock().unwrap() = Some(reason);
+                    continue;
+                }
+
+                let req_id = resp.request_id().to_string();
+
+                let mut p = pending_clone.lock().await;
+                let Some(route) = p.get_mut(&req_id) else { continue };
+                if route.delivery == Delivery::First {
+                    let _ = route.tx.try_send(resp);
+                    p.remove(&req_id);
+                    if p.is_empty() { reader_idle.notify_waiters(); }
+                    continue;
+                }
+                // Only chunks can arrive without bound; every queued one leaves a slot for the terminal
+                let last = match resp {
+                    RpcResponse::Accepted { .. } => continue,
+                    RpcResponse::Chunk { .. } if route.delivery != Delivery::Chunks => continue,
+                    // A peer streaming without end: stop here instead of reassembling forever
+                    RpcResponse::Chunk { .. } if route.chunks >= route.max_chunks => RpcResponse::Error {
+                        request_id: req_id.clone(), ok: false,
+                        error: format!("streamed response exceeded {} chunks", route.max_chunks), meta: HashMap::new(),
+                    },
+                    RpcResponse::Chunk { .. } if route.tx.capacity() > 1 => {
+                        route.chunks += 1;
+                        let _ = route.tx.try_send(resp);
+                        continue;
+                    }
+                    // The consumer fell too far behind: fail the stream rather than drop pages
+                    RpcResponse::Chunk { .. } => RpcResponse::Error {
+                        request_id: req_id.clone(), ok: false, error: "too many unconsumed chunks".into(), meta: HashMap::new(),
+                    },
+                    // On Completed/Error, we’re done—remove the entry.
+                    resp => resp,
+                };
+                let _ = route.tx.try_send(last);
+                p.remove(&req_id);
+                if p.is_empty() { reader_idle.notify_waiters(); }
+            }
+        }).abort_handle();
+
+        let cli = Self {
+            writer, pending, ready, ready_tx, idle, reader,
+            closing: AtomicBool::new(false),
+            default_params: Default::default(),
+            max_queued_chunks: DEFAULT_MAX_QUEUED_CHUNKS,
+            max_stream_chunks: DEFAULT_MAX_STREAM_CHUNKS,
+            max_stream_bytes: DEFAULT_MAX_STREAM_BYTES,
+            func_timeouts: HashMap::new(),
+            default_timeout: None,
+            max_pending: None,
+            next_seq: AtomicU64::new(0),
+            breaker: None,
+            retries: None,
+            clock: Arc::new(TokioClock),
+            goodbye,
+        };
+        let hello = cli.call("hello", json!({ "protocol": PROTOCOL_VERSION })).await
+            .map_err(|e| anyhow!("handshake failed: {e}"))?;
+        let server_protocol = hello.get("protocol").and_then(|v| v.as_u64());
+        if server_protocol != Some(PROTOCOL_VERSION as u64) {
+            return Err(anyhow!("handshake failed: server speaks protocol {server_protocol:?}, client {PROTOCOL_VERSION}"));
+        }
+        let _ = cli.ready_tx.send(true);
+        Ok(cli)
+    }
+
+    /// Shallow-merge the fields of `defaults` (an object) into the params of every later call,
+    /// e.g. an auth token or tenant id. Fields a call sets itself take precedence.
+    pub fn with_default_params(mut self, defaults: serde_json::Value) -> Self {
+        if let serde_json::Value::Object(map) = defaults {
+            self.default_params = map;
+        }
+        self
+    }
+
+    /// Cap how many chunks a streaming call (e.g. `sort_paged`) buffers while its consumer is
+    /// behind; past it the call fails with "too many unconsumed chunks". Other calls never
+    /// buffer chunks or acks, only their terminal response.
+    pub fn with_max_queued_chunks(mut self, max: usize) -> Self {
+        self.max_queued_chunks = max;
+        self
+    }
+
+    /// Cap one streaming call's whole response, however promptly it is consumed: past `chunks`
+    /// chunks it fails with "streamed response exceeded N chunks", and `sort_paged` pages or
+    /// `compress_data` output adding up to more than `bytes` fail it the same way.
+    pub fn with_max_stream(mut self, chunks: u64, bytes: usize) -> Self {
+        self.max_stream_chunks = chunks;
+        self.max_stream_bytes = bytes;
+        self
+    }
+
+    /// Give up on calls whose terminal response takes too long, with a budget per function
+    /// since a `matrix_multiply` can fairly take far longer than a `hash_compute`. A call to a
+    /// function in `per_func` gets that timeout, any other call `fallback` (none: wait forever).
+    /// `call_timeout` overrides both for one call. Streaming calls are not timed.
+    pub fn with_timeouts(mut self, fallback: Option<Duration>, per_func: HashMap<String, Duration>) -> Self {
+        self.default_timeout = fallback;
+        self.func_timeouts = per_func;
+        self
+    }
+
+    /// Watch for calls that never complete: with no per-call timeout, a call the server never
+    /// answers stays pending forever. Past `max` pending calls the client logs a warning and,
+    /// with `PendingOverflow::FailOldest`, fails the oldest with an "evicted" error to stay at
+    /// `max`. Set it well above the concurrency you expect; it is a leak detector, not a limiter.
+    pub fn with_max_pending(mut self, max: usize, overflow: PendingOverflow) -> Self {
+        self.max_pending = Some(PendingLimit { max: max.max(1), overflow });
+        self
+    }
+
+    /// Fail calls fast instead of hammering a failing server: after `threshold` consecutive
+    /// failed calls (`busy:` refusals, timeouts or a lost connection) within `window`, calls fail with
+    /// `ClientError::CircuitOpen` without being sent for `cooldown`. Then one probe call at a time
+    /// goes through; a success closes the circuit, a failure reopens it for another cooldown.
+    pub fn with_circuit_breaker(mut self, threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        self.breaker = Some(Arc::new(CircuitBreaker::new(threshold, window, cooldown, self.clock.clone())));
+        self
+    }
+
+    /// Retry calls the server refused as `busy:` (rate limited or queue full; never run), up to
+    /// `cfg.max_retries` times each. Retries across all calls share one budget of `cfg.budget`
+    /// tokens refilled at `cfg.refill_per_sec`, so a struggling server isn't buried in retries:
+    /// once it is spent, a refused call fails with the server's error instead of retrying.
+    /// Streaming calls (`sort_paged`, `compress_data`) are not retried.
+    pub fn with_retries(mut self, cfg: RetryConfig) -> Self {
+        self.retries = Some(Arc::new(RetryBudget::new(cfg, self.clock.clone())));
+        self
+    }
+
+    /// Read time for the circuit breaker and retry budget from `clock` (e.g. a `MockClock` in
+    /// tests) instead of tokio's.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        if let Some(b) = &self.breaker {
+            self.breaker = Some(Arc::new(CircuitBreaker::new(b.threshold, b.window, b.cooldown, clock.clone())));
+        }
+        if let Some(r) = &self.retries {
+            self.retries = Some(Arc::new(RetryBudget::new(r.cfg, clock.clone())));
+        }
+        self.clock = clock;
+        self
+    }
+
+    /// Current circuit breaker state; always `Closed` without `with_circuit_breaker`.
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.as_ref().map_or(BreakerState::Closed, |b| b.state())
+    }
+
+    /// Refuse new calls, give in-flight ones up to `timeout` to finish, then stop the reader and
+    /// close the socket. Calls still pending at the deadline fail; that case is also reported as an error.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<()> {
+        self.closing.store(true, Ordering::SeqCst);
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                let idle = self.idle.notified();
+                tokio::pin!(idle);
+                idle.as_mut().enable();
+                if self.pending.lock().await.is_empty() { break; }
+                idle.await;
+            }
+        }).await.is_ok();
+
+        self.reader.abort();
+        let _ = self.ready_tx.send(false);
+        let mut p = self.pending.lock().await;
+        let abandoned = p.len();
+        for (request_id, route) in p.drain() {
+            let _ = route.tx.try_send(RpcResponse::Error {
+                request_id, ok: false, error: "client shut down".into(), meta: HashMap::new(),
+            });
+        }
+        drop(p);
+        self.writer.lock().await.shutdown().await?;
+        if drained { Ok(()) } else { Err(anyhow!("shutdown timed out; {abandoned} call(s) abandoned")) }
+    }
+
+    /// The reason the server gave in a `goodbye` frame, once it has sent one: it closed the
+    /// connection on purpose (e.g. draining for a restart), so reconnecting, perhaps elsewhere,
+    /// is the thing to do. `None` while connected or after an unannounced close.
+    pub fn goodbye_reason(&self) -> Option<String> {
+        self.goodbye.lock().unwrap().clone()
+    }
+
+    /// Whether the handshake has completed and the connection is still up.
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Wait until the client is ready; errors if the connection closed first.
+    pub async fn wait_ready(&self) -> Result<()> {
+        if self.is_ready() {
+            return Ok(());
+        }
+        let mut ready = self.ready.clone();
+        ready.changed().await.map_err(|_| anyhow!("connection closed"))?;
+        if *ready.borrow() { Ok(()) } else { Err(anyhow!("connection closed")) }
+    }
+
+    /// Send a request and return the channel its responses arrive on: the terminal one, and
+    /// more as `delivery` asks.
+    async fn send<P: Serialize>(
+        &self,
+        func: &str,
+        params: P,
+        idempotency_key: Option<&str>,
+        delivery: Delivery,
+    ) -> Result<mpsc::Receiver<RpcResponse>> {
+        if let Some(b) = &self.breaker {
+            b.admit()?;
+        }
+        let sent = self.send_merged(func, params, idempotency_key, delivery).await;
+        if let (Err(_), Some(b)) = (&sent, &self.breaker) {
+            b.record(false);
+        }
+        sent
+    }
+
+    /// `send` after the breaker check: merges default params into `params`.
+    async fn send_merged<P: Serialize>(
+        &self,
+        func: &str,
+        params: P,
+        idempotency_key: Option<&str>,
+        delivery: Delivery,
+    ) -> Result<mpsc::Receiver<RpcResponse>> {
+        if self.default_params.is_empty() {
+            return self.send_request(func, params, idempotency_key, delivery).await;
+        }
+        let mut params = serde_json::to_value(params)?;
+        if let serde_json::Value::Object(map) = &mut params {
+            for (k, v) in &self.default_params {
+                map.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+        }
+        self.send_request(func, params, idempotency_key, delivery).await
+    }
+
+    /// `send` without default params: `params` is serialized straight into the frame.
+    async fn send_request<P: Serialize>(
+        &self,
+        func: &str,
+        params: P,
+        idempotency_key: Option<&str>,
+        delivery: Delivery,
+    ) -> Result<mpsc::Receiver<RpcResponse>> {
+        let request_id = Uuid::new_v4().to_string();
+        let req = RpcRequest {
+            request_id: request_id.clone(),
+            func: func.to_string(),
+            params,
+            idempotency_key: idempotency_key.map(str::to_string),
+            compress_response_over: None,
+            meta: HashMap::new(),
+            dry_run: false,
+            priority: Default::default(),
+            pretty: false,
+        };
+
+        // Room for the queued chunks plus Completed/Error
+        let cap = if delivery == Delivery::Chunks { self.max_queued_chunks + 1 } else { 1 };
+        let (tx, rx) = mpsc::channel::<RpcResponse>(cap);
+        {
+            let mut p = self.pending.lock().await;
+            // Checked under the lock so `shutdown` can't miss a call that slips in
+            if self.closing.load(Ordering::SeqCst) {
+                return Err(anyhow!("client is shut down"));
+            }
+            p.insert(request_id.clone(), Route {
+                tx, delivery, seq: self.next_seq.fetch_add(1, Ordering::Relaxed), chunks: 0, max_chunks: self.max_stream_chunks,
+            });
+            if let Some(limit) = &self.max_pending {
+                limit.enforce(&mut p);
+            }
+        }
+
+        {
+            let mut w = self.writer.lock().await;
+            write_frame(&mut *w, &req).await?;
+            w.flush().await?;
+        }
+        Ok(rx)
+    }
+
+    pub async fn call(&self, func: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        let resp = self.call_full(func, params).await?;
+        Ok(Result::<serde_json::Value, ClientError>::from(resp)?)
+    }
+
+    /// Like `call`, failing with `ClientError::TimedOut` unless it completes within `timeout`,
+    /// whatever `with_timeouts` configured for `func`.
+    pub async fn call_timeout(&self, func: &str, params: serde_json::Value, timeout: Duration) -> Result<serde_json::Value> {
+        let resp = self.call_terminal_within(func, &params, None, Some(timeout)).await?;
+        Ok(Result::<serde_json::Value, ClientError>::from(resp)?)
+    }
+
+    /// Like `call`, with params of any `Serialize` type (e.g. a typed struct), written into the
+    /// request frame directly instead of going through a `serde_json::Value` first.
+    pub async fn call_with<P: Serialize>(&self, func: &str, params: &P) -> Result<serde_json::Value> {
+        let resp = self.call_terminal(func, params, None).await?;
+        Ok(Result::<serde_json::Value, ClientError>::from(resp)?)
+    }
+
+    /// Like `call`, but returns the terminal `Completed`/`Error` response with all its fields.
+    pub async fn call_full(&self, func: &str, params: serde_json::Value) -> Result<RpcResponse> {
+        self.call_terminal(func, &params, None).await
+    }
+
+    /// Like `call`, for a server that sends exactly one frame per request: one running with
+    /// `RPC_DISABLE_ACCEPTED`, asked for an operation that doesn't stream chunks. The first frame
+    /// back is taken as the response without looking for acks. Using it anywhere else is a bug:
+    /// an `Accepted` or `Chunk` arriving first fails the call and the real response is dropped.
+    pub async fn fast_call(&self, func: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        let mut rx = self.send(func, params, None, Delivery::First).await?;
+        let resp = rx.recv().await;
+        if let Some(b) = &self.breaker {
+            resp.as_ref().map_or_else(|| b.record(false), |r| b.record_response(r));
+        }
+        match resp.ok_or_else(|| anyhow!("connection closed"))? {
+            RpcResponse::Accepted { .. } | RpcResponse::Chunk { .. } => {
+                Err(anyhow!("fast_call got a non-terminal frame; the server must not send Accepted or chunks"))
+            }
+            resp => Ok(Result::<serde_json::Value, ClientError>::from(resp)?),
+        }
+    }
+
+    /// Like `call`, but safe to retry: the server replays the first outcome for the same key.
+    pub async fn call_idempotent(&self, func: &str, params: serde_json::Value, key: &str) -> Result<serde_json::Value> {
+        let resp = self.call_terminal(func, &params, Some(key)).await?;
+        Ok(Result::<serde_json::Value, ClientError>::from(resp)?)
+    }
+
+    /// Like `call`, for operations taking `data_base64` (e.g. `hash_compute`, `compress_data`),
+    /// with that input read from `body` instead of given in `params`. The body is sent in
+    /// `BODY_CHUNK_BYTES` pieces as it is read and reassembled by the server, so it never has
+    /// to be in client memory at once.
+    pub async fn call_streaming_body<R: AsyncRead + Unpin>(
+        &self,
+        func: &str,
+        mut params: serde_json::Value,
+        mut body: R,
+    ) -> Result<serde_json::Value> {
+        let serde_json::Value::Object(map) = &mut params else {
+            return Err(anyhow!("params must be an object"));
+        };
+        let begun = self.call("body_begin", json!({})).await?;
+        let body_id = begun.get("body_id").and_then(|v| v.as_str()).ok_or_else(|| anyhow!("missing body_id"))?;
+        let mut buf = vec![0u8; BODY_CHUNK_BYTES];
+        loop {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let n = body.read(&mut buf[filled..]).await?;
+                if n == 0 { break; }
+                filled += n;
+            }
+            if filled == 0 { break; }
+            // Awaited one at a time: the server applies appends in the order they finish
+            self.call("body_append", json!({ "body_id": body_id, "data_base64": B64.encode(&buf[..filled]) })).await?;
+        }
+        map.insert("data_body_id".into(), body_id.into());
+        self.call(func, params).await
+    }
+
+    /// Send a non-streaming call and wait for its terminal response, retrying `busy:` refusals
+    /// as `with_retries` allows.
+    async fn call_terminal<P: Serialize + ?Sized>(
+        &self,
+        func: &str,
+        params: &P,
+        idempotency_key: Option<&str>,
+    ) -> Result<RpcResponse> {
+        let timeout = self.func_timeouts.get(func).copied().or(self.default_timeout);
+        self.call_terminal_within(func, params, idempotency_key, timeout).await
+    }
+
+    /// `call_terminal` with each attempt limited to `timeout`.
+    async fn call_terminal_within<P: Serialize + ?Sized>(
+        &self,
+        func: &str,
+        params: &P,
+        idempotency_key: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<RpcResponse> {
+        let mut retries = 0;
+        loop {
+            let rx = self.send(func, params, idempotency_key, Delivery::Terminal).await?;
+            let resp = match timeout {
+                None => self.terminal(rx).await?,
+                Some(after) => match tokio::time::timeout(after, self.terminal(rx)).await {
+                    Ok(resp) => resp?,
+                    Err(_) => {
+                        if let Some(b) = &self.breaker { b.record(false); }
+                        self.forget_abandoned().await;
+                        return Err(ClientError::TimedOut { func: func.to_string(), after }.into());
+                    }
+                },
+            };
+            let Some(budget) = &self.retries else { return Ok(resp) };
+            if !is_busy(&resp) || retries >= budget.cfg.max_retries || !budget.try_spend() {
+                return Ok(resp);
+            }
+            retries += 1;
+            tokio::time::sleep(budget.cfg.backoff).await;
+        }
+    }
+
+    /// Drop the routes of calls whose caller stopped waiting, so they don't count as pending.
+    async fn forget_abandoned(&self) {
+        let mut p = self.pending.lock().await;
+        p.retain(|_, route| !route.tx.is_closed());
+        if p.is_empty() { self.idle.notify_waiters(); }
+    }
+
+    async fn terminal(&self, mut rx: mpsc::Receiver<RpcResponse>) -> Result<RpcResponse> {
+        // Drain Accepted (and any stray chunks); wait for final
+        let resp = loop {
+            match rx.recv().await {
+                Some(RpcResponse::Accepted { .. } | RpcResponse::Chunk { .. }) => { /* ignore, keep waiting */ }
+                resp => break resp,
+            }
+        };
+        if let Some(b) = &self.breaker {
+            resp.as_ref().map_or_else(|| b.record(false), |r| b.record_response(r));
+        }
+        resp.ok_or_else(|| anyhow!("connection closed"))
+    }
+
+    // High-level wrappers. Each is a future that sends its request when first polled, so several
+    // can be joined to run at once on this connection, keeping their result types:
+    // `let (hex, sorted) = tokio::join!(cli.hash_compute(b"abc"), cli.sort_array(vec![2, 1]));`
+    pub async fn hash_compute(&self, data: &[u8]) -> Result<String> {
+        let v = self.call("hash_compute", json!({ "data_base64": B64.encode(data) })).await?;
+        Ok(v.get("hex").and_then(|x| x.as_str()).unwrap_or_default().to_string())
+    }
+    pub async fn sort_array(&self, values: Vec<i32>) -> Result<Vec<i32>> {
+        let v = self.call("sort_array", json!({ "values": values })).await?;
+        Ok(serde_json::from_value(v.get("values").cloned().ok_or_else(|| anyhow!("missing values"))?)?)
+    }
+    /// Sort server-side and consume the result lazily, one page at a time.
+    pub async fn sort_paged(&self, values: Vec<i32>, page_size: usize) -> Result<PageStream> {
+        let rx = self.send("sort_paged", json!({ "values": values, "page_size": page_size }), None, Delivery::Chunks).await?;
+        Ok(PageStream { rx, done: false, breaker: self.breaker.clone(), bytes: 0, max_bytes: self.max_stream_bytes })
+    }
+    /// `sort_paged` as a `Stream` of pages, each yielded as it arrives. The request goes out when
+    /// the stream is first polled; failing to send it is the stream's only item.
+    pub fn sort_array_stream(&self, values: Vec<i32>, page_size: usize) -> impl Stream<Item = Result<Vec<i32>>> + '_ {
+        futures_util::stream::once(self.sort_paged(values, page_size)).flat_map(|pages| match pages {
+            Ok(pages) => pages.into_stream().left_stream(),
+            Err(e) => futures_util::stream::iter([Err(e)]).right_stream(),
+        })
+    }
+    pub async fn matrix_multiply(&self, n: usize, a: Vec<f64>, b: Vec<f64>) -> Result<Vec<f64>> {
+        let v = self.call("matrix_multiply", json!({ "n": n, "a": a, "b": b })).await?;
+        Ok(serde_json::from_value(v.get("c").cloned().ok_or_else(|| anyhow!("missing c"))?)?)
+    }
+    /// Output too large for one response frame arrives as chunks and is reassembled here.
+    pub async fn compress_data(&self, algo: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let mut rx = self.send("compress_data", json!({ "algo": algo, "data_base64": B64.encode(data) }), None, Delivery::Chunks).await?;
+        let piece = |v: &serde_json::Value| -> Result<Vec<u8>> {
+            let s = v.get("compressed_base64").and_then(|x| x.as_str()).ok_or_else(|| anyhow!("missing compressed_base64"))?;
+            Ok(B64.decode(s.as_bytes())?)
+        };
+        let mut out = Vec::new();
+        loop {
+            let resp = rx.recv().await;
+            match resp {
+                Some(RpcResponse::Accepted { .. }) => {}
+                Some(RpcResponse::Chunk { data, .. }) => {
+                    out.extend(piece(&data)?);
+                    if out.len() > self.max_stream_bytes {
+                        return Err(anyhow!("streamed response exceeded {} bytes", self.max_stream_bytes));
+                    }
+                }
+                _ => {
+                    if let Some(b) = &self.breaker {
+                        resp.as_ref().map_or_else(|| b.record(false), |r| b.record_response(r));
+                    }
+                    let v = Result::<serde_json::Value, ClientError>::from(resp.ok_or_else(|| anyhow!("connection closed"))?)?;
+                    if v.get("chunks").is_some() {
+                        return Ok(out);
+                    }
+                    return piece(&v);
+                }
+            }
+        }
+    }
+}
+
+/// Pages of a `sort_paged` result, in order.
+pub struct PageStream {
+    rx: mpsc::Receiver<RpcResponse>,
+    done: bool,
+    breaker: Option<Arc<CircuitBreaker>>,
+    /// Size of the pages yielded so far, against `max_bytes`
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl PageStream {
+    /// Next page, or `None` once the server has completed the request.
+    pub async fn next_page(&mut self) -> Option<Result<Vec<i32>>> {
+        while !self.done {
+            let Some(resp) = self.rx.recv().await else {
+                self.done = true;
+                if let Some(b) = &self.breaker { b.record(false); }
+                return Some(Err(anyhow!("connection closed")));
+            };
+            match resp {
+                RpcResponse::Accepted { .. } => {}
+                RpcResponse::Chunk { data, .. } => {
+                    let page = data.get("values").cloned().ok_or_else(|| anyhow!("missing values"));
+                    let page: Result<Vec<i32>> = page.and_then(|v| Ok(serde_json::from_value(v)?));
+                    if let Ok(values) = &page {
+                        self.bytes += std::mem::size_of_val(values.as_slice());
+                        if self.bytes > self.max_bytes {
+                            self.done = true;
+                            return Some(Err(anyhow!("streamed response exceeded {} bytes", self.max_bytes)));
+                        }
+                    }
+                    return Some(page);
+                }
+                resp => {
+                    self.done = true;
+                    if let Some(b) = &self.breaker { b.record_response(&resp); }
+                    if let Err(e) = Result::<serde_json::Value, ClientError>::from(resp) {
+                        return Some(Err(e.into()));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The remaining pages as a `Stream`, ending after the last one or the first error.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Vec<i32>>> {
+        futures_util::stream::unfold(self, |mut pages| async move { pages.next_page().await.map(|page| (page, pages)) })
+    }
+
+    /// Drain every page into one sorted vector.
+    pub async fn collect(mut self) -> Result<Vec<i32>> {
+        let mut out = Vec::new();
+        while let Some(page) = self.next_page().await {
+            out.extend(page?);
+        }
+        Ok(out)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_frame, resp_accepted, resp_ok};
+    use tokio::net::TcpListener;
+
+    /// Minimal two-phase server: answers `hello`, otherwise acks then echoes the params back.
+    /// Yields the request_id of every non-handshake request.
+    async fn echo_server() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (ids_tx, ids) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            while let Ok(v) = read_frame(&mut sock).await {
+                let req: RpcRequest = serde_json::from_value(v).unwrap();
+                write_frame(&mut sock, &resp_accepted(&req.request_id)).await.unwrap();
+                let result = if req.func == "hello" {
+                    json!({ "protocol": PROTOCOL_VERSION })
+                } else {
+                    let _ = ids_tx.send(req.request_id.clone());
+                    req.params
+                };
+                write_frame(&mut sock, &resp_ok(&req.request_id, result)).await.unwrap();
+            }
+        });
+        (addr, ids)
+    }
+
+    #[tokio::test]
+    async fn test_call_full_returns_completed() {
+        let (addr, mut ids) = echo_server().await;
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        let resp = cli.call_full("echo", json!({ "x": 1 })).await.unwrap();
+        let sent_id = ids.recv().await.unwrap();
+        match resp {
+            RpcResponse::Completed { request_id, ok, result, .. } => {
+                assert_eq!(request_id, sent_id);
+                assert!(ok);
+                assert_eq!(result, Some(json!({ "x": 1 })));
+            }
+            other => panic!("expected Completed, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_with_typed_params_matches_value_call() {
+        #[derive(Serialize)]
+        struct SortParams<'a> {
+            values: &'a [i32],
+        }
+        let (addr, _ids) = echo_server().await;
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        let typed = cli.call_with("sort_array", &SortParams { values: &[3, 1, 2] }).await.unwrap();
+        let value = cli.call("sort_array", json!({ "values": [3, 1, 2] })).await.unwrap();
+        assert_eq!(typed, value);
+
+        // Default params still merge into typed params
+        let cli = cli.with_default_params(json!({ "tenant": "acme" }));
+        let typed = cli.call_with("sort_array", &SortParams { values: &[1] }).await.unwrap();
+        assert_eq!(typed, json!({ "values": [1], "tenant": "acme" }));
+    }
+
+    /// Answers `hello`; for anything else sends `params.chunks` (default 100) chunks, then completes.
+    /// Each chunk carries one value as a page and three zero bytes as a `compress_data` piece.
+    async fn flooding_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            while let Ok(v) = read_frame(&mut sock).await {
+                let req: RpcRequest = serde_json::from_value(v).unwrap();
+                if req.func == "hello" {
+                    write_frame(&mut sock, &resp_ok(&req.request_id, json!({ "protocol": PROTOCOL_VERSION }))).await.unwrap();
+                    continue;
+                }
+                let mut frames = Vec::new();
+                for seq in 0..req.params["chunks"].as_u64().unwrap_or(100) {
+                    let data = json!({ "values": [seq], "compressed_base64": "AAAA" });
+                    write_frame(&mut frames, &crate::resp_chunk(&req.request_id, seq, data)).await.unwrap();
+                }
+                write_frame(&mut frames, &resp_ok(&req.request_id, json!("done"))).await.unwrap();
+                sock.write_all(&frames).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_chunk_flood_stays_bounded() {
+        let addr = flooding_server().await;
+        let cli = RpcClient::connect(&addr).await.unwrap().with_max_queued_chunks(8);
+
+        // A plain call never queues the chunks and still gets its result
+        assert_eq!(cli.call("flood", json!({ "chunks": 20_000 })).await.unwrap(), json!("done"));
+
+        // An unread page stream holds at most the cap, then fails instead of growing
+        let stream = cli.sort_paged(vec![], 1).await.unwrap();
+        let _ = cli.call("flood", json!({ "chunks": 0 })).await; // barrier: the flood has been read
+        assert!(stream.rx.len() <= 9, "queued {}", stream.rx.len());
+        let err = stream.collect().await.unwrap_err();
+        assert!(err.to_string().contains("too many unconsumed chunks"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_stream_past_cap_is_aborted() {
+        let addr = flooding_server().await;
+        let cli = RpcClient::connect(&addr).await.unwrap().with_max_stream(10, 35);
+        // Each page is one 4-byte value, so the ninth page passes the byte cap
+        let err = cli.sort_paged(vec![], 1).await.unwrap().collect().await.unwrap_err();
+        assert_eq!(err.to_string(), "streamed response exceeded 35 bytes");
+        // Each piece is 3 bytes, so the chunk cap is hit first
+        let err = cli.compress_data("zlib", b"").await.unwrap_err();
+        assert_eq!(err.to_string(), "streamed response exceeded 10 chunks");
+        // The aborted calls' remaining chunks are dropped and the connection is still usable
+        assert_eq!(cli.call("flood", json!({ "chunks": 0 })).await.unwrap(), json!("done"));
+    }
+
+    /// Answers `hello` and nothing else, so every other call stays pending.
+    async fn silent_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            while let Ok(v) = read_frame(&mut sock).await {
+                let req: RpcRequest = serde_json::from_value(v).unwrap();
+                if req.func == "hello" {
+                    write_frame(&mut sock, &resp_ok(&req.request_id, json!({ "protocol": PROTOCOL_VERSION }))).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_follow_the_called_function() {
+        let addr = silent_server().await;
+        let timeouts = HashMap::from([
+            ("hash_compute".to_string(), Duration::from_millis(50)),
+            ("matrix_multiply".to_string(), Duration::from_millis(400)),
+        ]);
+        let cli = RpcClient::connect(&addr).await.unwrap().with_timeouts(Some(Duration::from_millis(200)), timeouts);
+        let timed = |func: &'static str| {
+            let cli = &cli;
+            async move {
+                let started = Instant::now();
+                let err = cli.call(func, json!({})).await.unwrap_err();
+                let after = match err.downcast_ref::<ClientError>() {
+                    Some(ClientError::TimedOut { after, .. }) => *after,
+                    _ => panic!("{err}"),
+                };
+                (after, started.elapsed())
+            }
+        };
+        let (hash, matmul, other) = tokio::join!(timed("hash_compute"), timed("matrix_multiply"), timed("sort_array"));
+        assert_eq!((hash.0, matmul.0, other.0), (Duration::from_millis(50), Duration::from_millis(400), Duration::from_millis(200)));
+        assert!(hash.1 >= hash.0 && hash.1 < other.0, "{:?}", hash.1);
+        assert!(other.1 >= other.0 && other.1 < matmul.0, "{:?}", other.1);
+        assert!(matmul.1 >= matmul.0, "{:?}", matmul.1);
+
+        // An explicit timeout wins over the function's, and abandoned calls aren't left pending
+        let err = cli.call_timeout("matrix_multiply", json!({}), Duration::from_millis(10)).await.unwrap_err();
+        assert_eq!(err.to_string(), "call to 'matrix_multiply' timed out after 10ms");
+        assert!(cli.pending.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_cap_warns_and_fails_oldest() {
+        let (logs, _guard) = crate::server::tests::capture_logs();
+        let addr = silent_server().await;
+
+        let cli = RpcClient::connect(&addr).await.unwrap().with_max_pending(3, PendingOverflow::FailOldest);
+        let mut calls = Vec::new();
+        for _ in 0..5 {
+            calls.push(cli.send("never", json!({}), None, Delivery::Terminal).await.unwrap());
+        }
+        assert_eq!(cli.pending.lock().await.len(), 3);
+        for (i, rx) in calls.iter_mut().enumerate() {
+            match rx.try_recv() {
+                Ok(RpcResponse::Error { error, .. }) if i < 2 => assert_eq!(error, "evicted: more than 3 calls pending"),
+                Err(mpsc::error::TryRecvError::Empty) if i >= 2 => {}
+                other => panic!("call {i}: {other:?}"),
+            }
+        }
+        assert_eq!(logs.text().matches("failing the oldest").count(), 2);
+
+        // Warn-only keeps every call, and warns once for the crossing rather than per call
+        let addr = silent_server().await;
+        let cli = RpcClient::connect(&addr).await.unwrap().with_max_pending(2, PendingOverflow::Warn);
+        let mut calls = Vec::new();
+        for _ in 0..5 {
+            calls.push(cli.send("never", json!({}), None, Delivery::Terminal).await.unwrap());
+        }
+        assert_eq!(cli.pending.lock().await.len(), 5);
+        assert_eq!(logs.text().matches("cap of 2").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_trips_fails_fast_and_recovers() {
+        // Answers `hello`; refuses `fail` calls as busy, echoes the rest, and counts every non-handshake request
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server_hits = hits.clone();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            while let Ok(v) = read_frame(&mut sock).await {
+                let req: RpcRequest = serde_json::from_value(v).unwrap();
+                let resp = match req.func.as_str() {
+                    "hello" => resp_ok(&req.request_id, json!({ "protocol": PROTOCOL_VERSION })),
+                    "fail" => {
+                        server_hits.fetch_add(1, Ordering::SeqCst);
+                        crate::resp_err(&req.request_id, "busy: server request queue is full, retry later")
+                    }
+                    _ => {
+                        server_hits.fetch_add(1, Ordering::SeqCst);
+                        resp_ok(&req.request_id, req.params)
+                    }
+                };
+                write_frame(&mut sock, &resp).await.unwrap();
+            }
+        });
+        let clock = crate::MockClock::new();
+        let cooldown = Duration::from_secs(30);
+        let cli = RpcClient::connect(&addr).await.unwrap()
+            .with_circuit_breaker(3, Duration::from_secs(5), cooldown)
+            .with_clock(Arc::new(clock.clone()));
+
+        for _ in 0..3 {
+            assert_eq!(cli.breaker_state(), BreakerState::Closed);
+            assert!(cli.call("fail", json!(null)).await.is_err());
+        }
+        assert_eq!(cli.breaker_state(), BreakerState::Open);
+
+        // Open: fails fast without reaching the server
+        let err = cli.call("echo", json!(1)).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ClientError>(), Some(ClientError::CircuitOpen)), "{err}");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // After the cooldown (no real waiting) a failed probe reopens the circuit
+        clock.advance(cooldown);
+        assert_eq!(cli.breaker_state(), BreakerState::HalfOpen);
+        assert!(cli.call("fail", json!(null)).await.is_err());
+        assert_eq!(cli.breaker_state(), BreakerState::Open);
+        assert!(cli.call("echo", json!(2)).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+        // ...and a successful one closes it
+        clock.advance(cooldown);
+        assert_eq!(cli.call("echo", json!(3)).await.unwrap(), json!(3));
+        assert_eq!(cli.breaker_state(), BreakerState::Closed);
+        assert_eq!(cli.call("echo", json!(4)).await.unwrap(), json!(4));
+        assert_eq!(hits.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_leave_circuit_closed() {
+        let addr = crate::server::tests::spawn_server().await.to_string();
+        let cli = RpcClient::connect(&addr).await.unwrap().with_circuit_breaker(2, Duration::from_secs(5), Duration::from_secs(30));
+        for _ in 0..3 {
+            assert!(cli.call("sort_array", json!({ "values": "not an array" })).await.is_err());
+            assert!(cli.call("no_such_func", json!({})).await.is_err());
+        }
+        assert_eq!(cli.breaker_state(), BreakerState::Closed);
+        assert_eq!(cli.call("sort_array", json!({ "values": [2, 1] })).await.unwrap(), json!({ "values": [1, 2] }));
+    }
+
+    #[tokio::test]
+    async fn test_retries_stay_within_shared_budget() {
+        // Answers `hello`; refuses everything else as busy, or fails `fail`, counting each request
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server_hits = hits.clone();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            while let Ok(v) = read_frame(&mut sock).await {
+                let req: RpcRequest = serde_json::from_value(v).unwrap();
+                let resp = match req.func.as_str() {
+                    "hello" => resp_ok(&req.request_id, json!({ "protocol": PROTOCOL_VERSION })),
+                    "fail" => {
+                        server_hits.fetch_add(1, Ordering::SeqCst);
+                        crate::resp_err(&req.request_id, "internal failure")
+                    }
+                    _ => {
+                        server_hits.fetch_add(1, Ordering::SeqCst);
+                        crate::resp_err(&req.request_id, "busy: server request queue is full, retry later")
+                    }
+                };
+                write_frame(&mut sock, &resp).await.unwrap();
+            }
+        });
+        let clock = crate::MockClock::new();
+        let cfg = RetryConfig { max_retries: 3, backoff: Duration::ZERO, budget: 5, refill_per_sec: 1.0 };
+        let cli = Arc::new(RpcClient::connect(&addr).await.unwrap()
+            .with_retries(cfg)
+            .with_clock(Arc::new(clock.clone())));
+
+        // 20 failing calls at once would make 80 attempts unbudgeted; the budget allows 5 retries
+        let mut calls = tokio::task::JoinSet::new();
+        for i in 0..20 {
+            let cli = cli.clone();
+            calls.spawn(async move { cli.call("work", json!(i)).await });
+        }
+        while let Some(res) = calls.join_next().await {
+            let err = res.unwrap().unwrap_err();
+            assert!(err.to_string().starts_with("busy:"), "{err}");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 25);
+
+        // Errors other than busy are never retried
+        assert!(cli.call("fail", json!(null)).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 26);
+
+        // The budget refills over time, up to its size
+        clock.advance(Duration::from_secs(2));
+        assert!(cli.call("work", json!(0)).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 29);
+        clock.advance(Duration::from_secs(60));
+        assert!(cli.call("work", json!(0)).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 33);
+    }
+
+    #[tokio::test]
+    async fn test_default_params_merged_under_call_params() {
+        let (addr, _ids) = echo_server().await;
+        let cli = RpcClient::connect(&addr).await.unwrap()
+            .with_default_params(json!({ "tenant": "acme", "region": "eu" }));
+        let echoed = cli.call("echo", json!({ "x": 1, "region": "us" })).await.unwrap();
+        assert_eq!(echoed, json!({ "x": 1, "tenant": "acme", "region": "us" }));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_in_flight_then_refuses_calls() {
+        // Like echo_server, but holds each non-handshake result for a while
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            while let Ok(v) = read_frame(&mut sock).await {
+                let req: RpcRequest = serde_json::from_value(v).unwrap();
+                let result = if req.func == "hello" {
+                    json!({ "protocol": PROTOCOL_VERSION })
+                } else {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    req.params
+                };
+                write_frame(&mut sock, &resp_ok(&req.request_id, result)).await.unwrap();
+            }
+        });
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        let (in_flight, shut) = tokio::join!(cli.call("slow", json!(1)), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cli.shutdown(Duration::from_secs(2)).await
+        });
+        assert_eq!(in_flight.unwrap(), json!(1));
+        shut.unwrap();
+        assert!(!cli.is_ready());
+        let err = cli.call("after", json!(2)).await.unwrap_err();
+        assert!(err.to_string().contains("shut down"));
+    }
+
+    #[tokio::test]
+    async fn test_works_against_server_without_accepted() {
+        use crate::server::{serve_listener, ServerConfig};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut cfg = ServerConfig::default();
+        cfg.disable_accepted = true;
+        let server = tokio::spawn(serve_listener(listener, cfg));
+
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        assert_eq!(cli.sort_array(vec![3, 1, 2]).await.unwrap(), vec![1, 2, 3]);
+        assert_eq!(cli.sort_paged(vec![4, 3, 2, 1], 2).await.unwrap().collect().await.unwrap(), vec![1, 2, 3, 4]);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_sort_array_stream_yields_sorted_pages() {
+        use crate::server::{serve_listener, ServerConfig};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(serve_listener(listener, ServerConfig::default()));
+
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        let values: Vec<i32> = (0..1000).map(|i| (i * 7919) % 1000 - 500).collect();
+        let pages: Vec<Vec<i32>> = cli.sort_array_stream(values.clone(), 64)
+            .map(|page| page.unwrap())
+            .collect().await;
+        assert_eq!(pages.len(), 16);
+        assert!(pages.iter().all(|p| p.len() <= 64));
+        let all: Vec<i32> = pages.concat();
+        assert!(all.is_sorted());
+        let mut expected = values;
+        expected.sort_unstable();
+        assert_eq!(all, expected);
+
+        // A server-side failure ends the stream with that error
+        let items: Vec<_> = cli.sort_array_stream(vec![1], 0).collect().await;
+        assert_eq!(items.len(), 1);
+        assert!(items[0].as_ref().unwrap_err().to_string().contains("page_size"), "{:?}", items[0]);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_fast_call_takes_the_single_response() {
+        use crate::server::{serve_listener, ServerConfig};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut cfg = ServerConfig::default();
+        cfg.disable_accepted = true;
+        let server = tokio::spawn(serve_listener(listener, cfg));
+
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        assert_eq!(cli.fast_call("sort_array", json!({ "values": [3, 1, 2] })).await.unwrap(), json!({ "values": [1, 2, 3] }));
+        let err = cli.fast_call("nope", json!({})).await.unwrap_err();
+        assert!(err.to_string().contains("unknown function"), "{err}");
+        server.abort();
+
+        // Against a server that acks, the ack is the one frame read: a misuse, reported as such
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(serve_listener(listener, ServerConfig::default()));
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        let err = cli.fast_call("sort_array", json!({ "values": [1] })).await.unwrap_err();
+        assert!(err.to_string().contains("non-terminal frame"), "{err}");
+        // The dropped response doesn't leak into later calls
+        assert_eq!(cli.sort_array(vec![2, 1]).await.unwrap(), vec![1, 2]);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_goodbye_reason_reaches_client() {
+        use crate::server::{serve_listener, ServerConfig};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let cfg = ServerConfig::default();
+        let shutdown = cfg.shutdown.clone();
+        let server = tokio::spawn(serve_listener(listener, cfg));
+
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        assert_eq!(cli.goodbye_reason(), None);
+        shutdown.drain("server draining");
+        server.await.unwrap().unwrap();
+
+        cli.ready.clone().wait_for(|ready| !ready).await.unwrap();
+        assert_eq!(cli.goodbye_reason().as_deref(), Some("server draining"));
+    }
+
+    /// A transport the crate knows nothing about: an in-memory pipe that counts bytes written.
+    struct CountingPipe {
+        pipe: tokio::io::DuplexStream,
+        written: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    struct CountingWriter {
+        inner: tokio::io::WriteHalf<tokio::io::DuplexStream>,
+        written: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+            let res = std::pin::Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let std::task::Poll::Ready(Ok(n)) = res {
+                self.written.fetch_add(n, Ordering::SeqCst);
+            }
+            res
+        }
+        fn poll_flush(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        }
+        fn poll_shutdown(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    impl Transport for CountingPipe {
+        type Read = tokio::io::ReadHalf<tokio::io::DuplexStream>;
+        type Write = CountingWriter;
+
+        fn split(self) -> (Self::Read, Self::Write) {
+            let (rd, wr) = tokio::io::split(self.pipe);
+            (rd, CountingWriter { inner: wr, written: self.written })
+        }
+
+        fn peer(&self) -> String {
+            "counting pipe".into()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_typed_calls_join_on_one_connection() {
+        use crate::server::{serve_listener, ServerConfig};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve_listener(listener, ServerConfig::default()));
+        let cli = RpcClient::connect(&addr).await.unwrap();
+
+        let (hex, sorted, product) = tokio::join!(
+            cli.hash_compute(b"abc"),
+            cli.sort_array(vec![3, 1, 2]),
+            cli.matrix_multiply(1, vec![2.0], vec![4.0]),
+        );
+        let (hex, sorted, product): (String, Vec<i32>, Vec<f64>) = (hex.unwrap(), sorted.unwrap(), product.unwrap());
+        assert_eq!(hex, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(sorted, vec![1, 2, 3]);
+        assert_eq!(product, vec![8.0]);
+    }
+
+    #[tokio::test]
+    async fn test_client_runs_over_custom_transport() {
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(crate::server::serve_transport(far, Arc::new(crate::server::ServerConfig::default())));
+        let written = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let cli = RpcClient::over(CountingPipe { pipe: near, written: written.clone() }).await.unwrap();
+        assert!(cli.is_ready());
+        assert_eq!(cli.sort_array(vec![3, 1, 2]).await.unwrap(), vec![1, 2, 3]);
+        assert_eq!(cli.hash_compute(b"abc").await.unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // The handshake and both calls went out through the mock's writer
+        assert!(written.load(Ordering::SeqCst) > 0);
+
+        // Closing our end ends the server's connection (as EOF, like a TCP client hanging up)
+        cli.shutdown(Duration::from_secs(1)).await.unwrap();
+        server.await.unwrap().unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_streaming_body_hashes_large_reader() {
+        use crate::server::{serve_listener, ServerConfig};
+        use sha2::{Digest, Sha256};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(serve_listener(listener, ServerConfig::default()));
+
+        // Several chunks' worth, not a multiple of the chunk size, read in small pieces like a file
+        let data: Vec<u8> = (0..3 * BODY_CHUNK_BYTES + 12_345).map(|i| (i * 31 % 251) as u8).collect();
+        let (reader, mut writer) = tokio::io::duplex(8192);
+        let feed = data.clone();
+        tokio::spawn(async move { writer.write_all(&feed).await.unwrap() });
+
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        let out = cli.call_streaming_body("hash_compute", json!({}), reader).await.unwrap();
+        assert_eq!(out["hex"].as_str().unwrap(), hex::encode(Sha256::digest(&data)));
+
+        // An empty body works too; params must be an object to carry the body reference
+        let out = cli.call_streaming_body("hash_compute", json!({}), &b""[..]).await.unwrap();
+        assert_eq!(out["hex"].as_str().unwrap(), hex::encode(Sha256::digest(b"")));
+        assert!(cli.call_streaming_body("hash_compute", json!(null), &b"x"[..]).await.is_err());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_call_right_after_connect_is_ready() {
+        let (addr, _ids) = echo_server().await;
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        assert!(cli.is_ready());
+        assert_eq!(cli.call("echo", json!(7)).await.unwrap(), json!(7));
+        cli.wait_ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failure_is_connect_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let req: RpcRequest = serde_json::from_value(read_frame(&mut sock).await.unwrap()).unwrap();
+            let resp = crate::resp_err(&req.request_id, "unknown function 'hello'");
+            write_frame(&mut sock, &resp).await.unwrap();
+        });
+        let err = RpcClient::connect(&addr).await.err().expect("connect should fail");
+        assert!(err.to_string().contains("handshake failed"));
+    }
+
+    #[tokio::test]
+    async fn test_pending_calls_fail_with_their_own_request_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (ids_tx, mut ids) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let hello: RpcRequest = serde_json::from_value(read_frame(&mut sock).await.unwrap()).unwrap();
+            write_frame(&mut sock, &resp_ok(&hello.request_id, json!({ "protocol": PROTOCOL_VERSION }))).await.unwrap();
+            // Take two requests, acknowledge neither, then drop the connection
+            for _ in 0..2 {
+                let req: RpcRequest = serde_json::from_value(read_frame(&mut sock).await.unwrap()).unwrap();
+                let _ = ids_tx.send(req.request_id);
+            }
+        });
+        let cli = RpcClient::connect(&addr).await.unwrap();
+        let (a, b) = tokio::join!(cli.call_full("one", json!(1)), cli.call_full("two", json!(2)));
+        let mut sent = vec![ids.recv().await.unwrap(), ids.recv().await.unwrap()];
+        let mut got = Vec::new();
+        for resp in [a.unwrap(), b.unwrap()] {
+            match resp {
+                RpcResponse::Error { request_id, error, .. } => {
+                    assert_eq!(error, "connection closed");
+                    got.push(request_id);
+                }
+                other => panic!("expected Error, got {other:?}"),
+            }
+        }
+        sent.sort();
+        got.sort();
+        assert_eq!(got, sent);
+    }
+}
@@ -0,0 +1,40 @@
+//! The loadgen binary as a CI gate: exit status follows the SLO flags.
+
+use simple_rpc_rust::server::{serve_listener, ServerConfig};
+use tokio::net::TcpListener;
+use tokio::process::Command;
+
+/// Run loadgen for a second of `sort` requests against `addr` with extra `flags`.
+async fn loadgen(addr: &str, flags: &[&str]) -> std::process::Output {
+    // The run writes results/loadgen.csv under its working directory
+    let dir = std::env::temp_dir().join(format!("loadgen-slo-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_loadgen"))
+        .args([addr, "50", "1", "sort"])
+        .args(flags)
+        .current_dir(&dir)
+        .output()
+        .await
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    out
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_loadgen_exit_status_follows_slo() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = tokio::spawn(serve_listener(listener, ServerConfig::default()));
+
+    let met = loadgen(&addr, &["--p99-target-ms", "1000", "--max-error-rate=0"]).await;
+    let stdout = String::from_utf8_lossy(&met.stdout);
+    assert!(met.status.success(), "stdout: {stdout}\nstderr: {}", String::from_utf8_lossy(&met.stderr));
+    assert!(!stdout.contains("SLO FAILED"));
+
+    // No real request finishes within a nanosecond
+    let missed = loadgen(&addr, &["--p99-target-ms", "0.000001"]).await;
+    assert!(!missed.status.success());
+    assert!(String::from_utf8_lossy(&missed.stdout).contains("SLO FAILED: p99"));
+
+    server.abort();
+}
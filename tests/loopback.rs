@@ -0,0 +1,38 @@
+//! End to end: the real server and `RpcClient` talking over a loopback socket.
+
+use simple_rpc_rust::client::RpcClient;
+use simple_rpc_rust::server::{serve_listener, ServerConfig};
+use std::io::Read;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn test_client_and_server_over_loopback() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = tokio::spawn(serve_listener(listener, ServerConfig::default()));
+
+    let cli = RpcClient::connect(&addr).await.unwrap();
+
+    assert_eq!(
+        cli.hash_compute(b"abc").await.unwrap(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(cli.sort_array(vec![3, 1, -5, 7, 1]).await.unwrap(), vec![-5, 1, 1, 3, 7]);
+    let c = cli.matrix_multiply(2, vec![1.0, 2.0, 3.0, 4.0], vec![5.0, 6.0, 7.0, 8.0]).await.unwrap();
+    assert_eq!(c, vec![19.0, 22.0, 43.0, 50.0]);
+
+    let data = b"hello hello hello hello";
+    let compressed = cli.compress_data("zlib", data).await.unwrap();
+    let mut round_trip = Vec::new();
+    flate2::read::ZlibDecoder::new(&compressed[..]).read_to_end(&mut round_trip).unwrap();
+    assert_eq!(round_trip, data);
+
+    // Errors come back as errors, not hangs
+    assert!(cli.call("no_such_op", serde_json::json!({})).await.is_err());
+
+    cli.shutdown(Duration::from_secs(2)).await.unwrap();
+    assert!(!cli.is_ready());
+    server.abort();
+    assert!(server.await.unwrap_err().is_cancelled(), "server exited on its own");
+}
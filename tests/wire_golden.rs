@@ -0,0 +1,98 @@
+//! Golden frames: known requests and responses must encode to exactly the committed bytes
+//! (4-byte big-endian length prefix, then the JSON body), so a serde attribute or field-order
+//! change that would break non-Rust peers fails here first. After an intended wire change,
+//! rerun with `UPDATE_GOLDEN=1` to rewrite `tests/fixtures/frames/` and review the diff.
+
+use serde::Serialize;
+use simple_rpc_rust::{read_frame, write_frame, Priority, RpcRequest, RpcResponse};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/frames").join(format!("{name}.frame"))
+}
+
+/// Encode `value` and compare with the fixture `name`, then decode the fixture back to the same JSON.
+async fn check_golden<T: Serialize>(name: &str, value: &T) {
+    let mut frame = Vec::new();
+    write_frame(&mut frame, value).await.unwrap();
+    let path = fixture_path(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, &frame).unwrap();
+    }
+    let golden = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    assert_eq!(
+        String::from_utf8_lossy(&frame),
+        String::from_utf8_lossy(&golden),
+        "{name}: encoded frame differs from {}",
+        path.display()
+    );
+    assert_eq!(frame, golden, "{name}: length prefix differs");
+    assert_eq!(read_frame(&golden[..]).await.unwrap(), serde_json::to_value(value).unwrap());
+}
+
+fn meta() -> HashMap<String, String> {
+    HashMap::from([("trace".to_string(), "t-1".to_string())])
+}
+
+#[tokio::test]
+async fn test_request_frames_match_golden() {
+    check_golden("request_minimal", &RpcRequest {
+        request_id: "r1".into(),
+        func: "sort_array".into(),
+        params: serde_json::json!({ "values": [3, 1, 2] }),
+        idempotency_key: None,
+        compress_response_over: None,
+        meta: HashMap::new(),
+        dry_run: false,
+        priority: Priority::Normal,
+        pretty: false,
+    }).await;
+    check_golden("request_full", &RpcRequest {
+        request_id: "r2".into(),
+        func: "hash_compute".into(),
+        params: serde_json::json!({ "data_base64": "YWJj" }),
+        idempotency_key: Some("k1".into()),
+        compress_response_over: Some(4096),
+        meta: meta(),
+        dry_run: true,
+        priority: Priority::High,
+        pretty: true,
+    }).await;
+}
+
+#[tokio::test]
+async fn test_response_frames_match_golden() {
+    check_golden("accepted", &RpcResponse::Accepted { request_id: "r1".into(), ok: true }).await;
+    check_golden("chunk", &RpcResponse::Chunk {
+        request_id: "r1".into(),
+        seq: 0,
+        data: serde_json::json!({ "values": [1, 2] }),
+    }).await;
+    check_golden("completed", &RpcResponse::Completed {
+        request_id: "r1".into(),
+        ok: true,
+        result: Some(serde_json::json!({ "values": [1, 2, 3] })),
+        error: None,
+        received_at: None,
+        completed_at: None,
+        meta: HashMap::new(),
+    }).await;
+    check_golden("completed_full", &RpcResponse::Completed {
+        request_id: "r2".into(),
+        ok: true,
+        result: Some(serde_json::json!({ "hex": "ba78" })),
+        error: None,
+        received_at: Some(1_700_000_000_000),
+        completed_at: Some(1_700_000_000_005),
+        meta: meta(),
+    }).await;
+    check_golden("error", &RpcResponse::Error {
+        request_id: "r3".into(),
+        ok: false,
+        error: "unknown function: nope".into(),
+        meta: HashMap::new(),
+    }).await;
+    check_golden("goodbye", &RpcResponse::Goodbye { reason: "server draining".into() }).await;
+}